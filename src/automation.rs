@@ -0,0 +1,107 @@
+use serde::{Deserialize, Serialize};
+
+use crate::midi::TempoMap;
+
+/// The shape of the transition from a breakpoint to the next one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Curve {
+    #[default]
+    Linear,
+    /// Changes by the same ratio per second, which sounds even for frequencies and gains.
+    /// Falls back to linear if the values are zero or have different signs.
+    Exponential,
+    /// Keeps the value until the next breakpoint.
+    Step,
+}
+
+/// A position in the song, either in seconds or in MIDI ticks.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SongTime {
+    Seconds(f64),
+    /// Converted to seconds with the tempo map of the MIDI file.
+    Ticks(u32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Breakpoint {
+    pub time: SongTime,
+    pub value: f64,
+    #[serde(default)]
+    pub curve: Curve,
+}
+
+impl Breakpoint {
+    pub fn new(time: SongTime, value: f64, curve: Curve) -> Self {
+        Self { time, value, curve }
+    }
+}
+
+/// What an automation lane controls.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AutomationTarget {
+    /// The volume of the channel, which replaces `Channel::volume`.
+    Volume,
+    /// The pan of the channel, which replaces `Channel::pan` and MIDI pan.
+    Pan,
+    /// A named parameter of a processor of the channel, see `Processor::set_parameter`.
+    Parameter { processor: usize, name: String },
+}
+
+/// Changes a target over the song by interpolating between breakpoints.
+/// Before the first breakpoint the lane has its value, and after the last one it keeps its value.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AutomationLane {
+    pub target: AutomationTarget,
+    pub points: Vec<Breakpoint>,
+    /// (seconds, value, curve), sorted by time.
+    #[serde(skip)]
+    resolved: Vec<(f64, f64, Curve)>,
+}
+
+impl AutomationLane {
+    pub fn new(target: AutomationTarget, points: Vec<Breakpoint>) -> Self {
+        let mut lane = Self {
+            target,
+            points,
+            resolved: Vec::new(),
+        };
+        lane.resolve(&TempoMap::default());
+        lane
+    }
+    /// Converts the breakpoints to seconds. The DAW does this when the lane is added, when the MIDI file is set
+    /// and whenever it is reset.
+    pub fn resolve(&mut self, tempo_map: &TempoMap) {
+        self.resolved = self
+            .points
+            .iter()
+            .map(|x| {
+                let time = match x.time {
+                    SongTime::Seconds(seconds) => seconds,
+                    SongTime::Ticks(ticks) => tempo_map.seconds(ticks),
+                };
+                (time, x.value, x.curve)
+            })
+            .collect();
+        self.resolved.sort_by(|a, b| a.0.total_cmp(&b.0));
+    }
+    /// Returns the value at a time in seconds, or `None` if the lane has no breakpoints.
+    pub fn value_at(&self, time: f64) -> Option<f64> {
+        let index = self.resolved.partition_point(|x| x.0 <= time);
+        if index == 0 {
+            return self.resolved.first().map(|x| x.1);
+        }
+        let (start, from, curve) = self.resolved[index - 1];
+        let Some(&(end, to, _)) = self.resolved.get(index) else {
+            return Some(from);
+        };
+        let t = (time - start) / (end - start);
+        Some(match curve {
+            Curve::Step => from,
+            Curve::Exponential if from * to > 0.0 => from * (to / from).powf(t),
+            _ => from + (to - from) * t,
+        })
+    }
+}
@@ -1,11 +1,21 @@
+use std::collections::VecDeque;
+use std::io::Seek;
+use std::io::Write;
 use std::ops::Index;
 use std::ops::IndexMut;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::time::Duration;
 use std::time::Instant;
 
 use fundsp::prelude::*;
 use midly::Smf;
-use rayon::iter::IntoParallelIterator;
+use rayon::iter::IndexedParallelIterator;
+use rayon::iter::IntoParallelRefIterator;
 use rayon::iter::IntoParallelRefMutIterator;
 use rayon::iter::ParallelIterator;
 
@@ -13,14 +23,38 @@ use serde::{Deserialize, Serialize};
 
 use crate::prelude::*;
 
+/// The number of samples that are rendered at once by `render_daw`.
+pub const BLOCK_SIZE: usize = 512;
+
 #[derive(Clone)]
 pub struct DAW {
     channels: Vec<SynthChannel>,
     pub channel_count: usize,
     pub master: Channel,
-    time: f64,
-    delta_time: f64,
+    /// Group buses and effect returns. Channels are routed into them with `route` and `add_send`.
+    pub buses: Vec<Channel>,
+    sample_index: usize,
+    sample_rate: f64,
     pub duration: Duration,
+    /// The tempo map of the MIDI file, which converts the tick positions of automation lanes
+    /// and is passed to the processors, e.g. for `TempoDelay`.
+    pub tempo_map: TempoMap,
+    pub tail: TailSettings,
+    /// If set, `render_daw` also keeps every channel's signal before its processors, for dry stems.
+    pub record_dry: bool,
+    /// If set, `set_midi` creates channels for all tracks that don't have one yet.
+    pub gm_mapping: Option<GMMapping>,
+    /// If set, `set_midi` splits every track by MIDI channel, so that each channel gets its own `SynthChannel`.
+    /// Channels without an instrument are created from `gm_mapping`, or the default `GMMapping` if it is not set.
+    pub split_channels: bool,
+    /// Receives a summary of the MIDI assignment and the render. Nothing is printed if it is not set.
+    pub logger: Option<Arc<dyn Logger>>,
+    /// Mixes the realtime playback of `tick`.
+    mixer: Option<Mixer>,
+    /// The order in which `tick_signals` ticks the channels, see `update_sidechain_order`.
+    sidechain_order: Vec<usize>,
+    /// The output and pre-fader signal of every channel, filled by `tick_signals`.
+    signals: Vec<(Frame<f64, U2>, Frame<f64, U2>)>,
 }
 
 impl DAW {
@@ -29,48 +63,104 @@ impl DAW {
             channels: Vec::new(),
             channel_count: 0,
             master: Channel::new("Master".to_string(), 0, 1.0, 0.0, Vec::new()),
-            time: 0.0,
-            delta_time: 1.0 / DEFAULT_SR,
+            buses: Vec::new(),
+            sample_index: 0,
+            sample_rate: DEFAULT_SR,
             duration: Duration::ZERO,
+            tempo_map: TempoMap::default(),
+            tail: TailSettings::default(),
+            record_dry: false,
+            gm_mapping: None,
+            split_channels: false,
+            logger: None,
+            mixer: None,
+            sidechain_order: Vec::new(),
+            signals: Vec::new(),
         }
     }
-    pub fn set_midi(&mut self, midi: Smf) {
-        println!(
-            "MIDI has {} tracks, DAW has {} channels.",
-            midi.tracks.len(),
-            self.channel_count
-        );
+    /// Assigns the tracks of a MIDI file to the channels in order and returns how they were assigned.
+    /// The DAW is left unchanged if the MIDI file can't be rendered.
+    pub fn set_midi(&mut self, mut midi: Smf) -> Result<MidiReport, MidiError> {
+        if self.split_channels {
+            midi.tracks = MidiMsg::split_channels(&midi.tracks)?;
+        }
+        let (fixed_midi, duration) = MidiMsg::convert_smf(&midi)?;
+        let tempo_map = TempoMap::new(midi.header.timing, &midi.tracks)?;
 
-        let track_names = midi
+        let existing_channels = self.channel_count;
+        let mut tracks: Vec<TrackReport> = midi
             .tracks
             .iter()
-            .map(|x| MidiMsg::extract_track_name(x))
-            .collect::<Vec<String>>();
-
-        let (fixed_midi, duration) = MidiMsg::convert_smf(midi);
-        self.duration = duration + Duration::from_secs_f64(5.0);
-        println!(
-            "Determined duration of {:.2} seconds.",
-            duration.as_secs_f64()
-        );
+            .map(|x| TrackReport {
+                name: MidiMsg::extract_track_name(x),
+                midi_channel: MidiMsg::extract_track_channel(x),
+                program: MidiMsg::find_track_program(x),
+                channel: None,
+                auto_created: false,
+            })
+            .collect();
+
+        let mapping = self
+            .gm_mapping
+            .clone()
+            .or_else(|| self.split_channels.then(GMMapping::default));
+        if let Some(mapping) = mapping {
+            for track in midi.tracks.iter().skip(self.channel_count) {
+                let name = MidiMsg::extract_track_name(track);
+                match MidiMsg::extract_track_channel(track) {
+                    Some(channel) => {
+                        let program = MidiMsg::extract_track_program(track);
+                        let instrument = mapping.instrument(program, channel);
+                        self.add_instrument(name, instrument, 1.0, 0.0);
+                    }
+                    None => {
+                        self.add_channel(name, SinkSynth, Vec::new(), 1.0, 0.0);
+                    }
+                }
+            }
+        }
+
+        self.duration = duration;
+        self.set_tempo_map(tempo_map);
 
         for (i, channel) in self.channels.iter_mut().enumerate() {
-            if let Some(name) = track_names.get(i).cloned() {
-                println!("Channel {} has name: {}", i, name);
-                channel.channel.name = name;
+            if let Some(track) = tracks.get_mut(i) {
+                channel.channel.name = track.name.clone();
+                track.channel = Some(i);
+                track.auto_created = i >= existing_channels;
             }
 
-            let track = fixed_midi
-                .get(i)
-                .map(|x| x.iter().copied().collect())
-                .unwrap_or(Vec::new());
+            let track = fixed_midi.get(i).map(|x| x.to_vec()).unwrap_or(Vec::new());
+
+            channel.set_midi(track);
+        }
 
-            channel.synth.set_midi(track);
+        let report = MidiReport {
+            unmatched_tracks: (0..tracks.len())
+                .filter(|&i| tracks[i].channel.is_none() && tracks[i].midi_channel.is_some())
+                .collect(),
+            unused_channels: (tracks.len()..self.channel_count).collect(),
+            tracks,
+            duration,
+        };
+        log_to(&self.logger, || report.summary());
+        Ok(report)
+    }
+    /// Passes the tempo map to the processors and automation lanes of every channel, so that they
+    /// follow the tempo of the song without waiting for a reset, e.g. during realtime playback.
+    fn set_tempo_map(&mut self, tempo_map: TempoMap) {
+        for channel in self.channels.iter_mut() {
+            channel.channel.set_tempo_map(&tempo_map);
         }
+        for bus in self.buses.iter_mut() {
+            bus.set_tempo_map(&tempo_map);
+        }
+        self.master.set_tempo_map(&tempo_map);
+        self.tempo_map = tempo_map;
     }
-    pub fn set_midi_bytes(&mut self, bytes: &[u8]) {
-        let smf = Smf::parse(bytes).unwrap();
-        self.set_midi(smf);
+    pub fn set_midi_bytes(&mut self, bytes: &[u8]) -> Result<MidiReport, MidiError> {
+        let smf = Smf::parse(bytes)?;
+        self.set_midi(smf)
     }
     pub fn add_instrument(
         &mut self,
@@ -97,10 +187,9 @@ impl DAW {
     ) -> usize {
         let index = self.channel_count;
         self.channel_count += 1;
-        self.channels.push(SynthChannel::new(
-            Channel::new(name, index, volume, pan, processors),
-            synth,
-        ));
+        let mut channel = Channel::new(name, index, volume, pan, processors);
+        channel.set_tempo_map(&self.tempo_map);
+        self.channels.push(SynthChannel::new(channel, synth));
         index
     }
     pub fn add_channel<T>(
@@ -116,14 +205,227 @@ impl DAW {
     {
         self.add_channel_boxed(name, Box::new(synth), processors, volume, pan)
     }
-    pub fn tick_channels(&mut self) -> Vec<Frame<f64, U2>> {
-        let output = self
-            .channels
-            .iter_mut()
-            .map(|x| x.tick(self.time))
+    /// Replaces the synth and processors of a channel with those of an instrument.
+    /// The channel keeps its MIDI track, so this can be called after `set_midi`.
+    pub fn set_instrument(
+        &mut self,
+        channel: usize,
+        instrument: &dyn MidiInstrument,
+    ) -> Result<(), anyhow::Error> {
+        self.check_channel(channel)?;
+        self.channels[channel].set_instrument(instrument);
+        Ok(())
+    }
+    /// Adds a bus, e.g. a group for all strings or a shared reverb return, and returns its index.
+    /// Buses are mixed into the master unless they are routed into another bus.
+    pub fn add_bus(
+        &mut self,
+        name: String,
+        processors: Vec<Box<dyn Processor>>,
+        volume: f64,
+        pan: f64,
+    ) -> usize {
+        let index = self.buses.len();
+        let mut bus = Channel::new(name, index, volume, pan, processors);
+        bus.set_tempo_map(&self.tempo_map);
+        self.buses.push(bus);
+        index
+    }
+    /// Routes the output of a channel into a bus instead of the master.
+    pub fn route(&mut self, channel: usize, bus: usize) -> Result<(), anyhow::Error> {
+        self.check_bus(bus, None)?;
+        self[channel].output = Output::Bus(bus);
+        Ok(())
+    }
+    pub fn add_send(&mut self, channel: usize, send: AuxSend) -> Result<(), anyhow::Error> {
+        self.check_bus(send.bus, None)?;
+        self[channel].sends.push(send);
+        Ok(())
+    }
+    /// Routes the output of a bus into another bus. To prevent feedback, buses can only be routed into
+    /// buses that were added after them.
+    pub fn route_bus(&mut self, bus: usize, target: usize) -> Result<(), anyhow::Error> {
+        self.check_bus(target, Some(bus))?;
+        self.buses[bus].output = Output::Bus(target);
+        Ok(())
+    }
+    /// Adds a send from a bus to a bus that was added after it.
+    pub fn add_bus_send(&mut self, bus: usize, send: AuxSend) -> Result<(), anyhow::Error> {
+        self.check_bus(send.bus, Some(bus))?;
+        self.buses[bus].sends.push(send);
+        Ok(())
+    }
+    fn check_bus(&self, bus: usize, source: Option<usize>) -> Result<(), anyhow::Error> {
+        anyhow::ensure!(
+            bus < self.buses.len(),
+            "Bus {} doesn't exist, the DAW has {} buses.",
+            bus,
+            self.buses.len()
+        );
+        if let Some(source) = source {
+            anyhow::ensure!(
+                source < bus,
+                "Bus {} can't be routed into bus {}, buses can only be routed into later buses.",
+                source,
+                bus
+            );
+        }
+        Ok(())
+    }
+    /// Feeds the output of channel `source` to the processors of a channel as sidechain signal,
+    /// e.g. to duck the pads under the kick drum. The source is rendered even if it is muted.
+    pub fn set_sidechain(&mut self, channel: usize, source: usize) -> Result<(), anyhow::Error> {
+        self.check_channel(channel)?;
+        self.check_channel(source)?;
+        let mut current = Some(source);
+        for _ in 0..self.channels.len() {
+            let Some(x) = current else {
+                break;
+            };
+            anyhow::ensure!(
+                x != channel,
+                "Channel {} can't be the sidechain source of channel {}, as that would create a cycle.",
+                source,
+                channel
+            );
+            current = self.sidechain_source(x);
+        }
+        self[channel].sidechain = Some(source);
+        self.update_sidechain_order();
+        Ok(())
+    }
+    /// Feeds the output of channel `source` to the processors of a bus as sidechain signal.
+    pub fn set_bus_sidechain(&mut self, bus: usize, source: usize) -> Result<(), anyhow::Error> {
+        self.check_bus(bus, None)?;
+        self.check_channel(source)?;
+        self.buses[bus].sidechain = Some(source);
+        Ok(())
+    }
+    fn check_channel(&self, channel: usize) -> Result<(), anyhow::Error> {
+        anyhow::ensure!(
+            channel < self.channels.len(),
+            "Channel {} doesn't exist, the DAW has {} channels.",
+            channel,
+            self.channels.len()
+        );
+        Ok(())
+    }
+    fn sidechain_source(&self, channel: usize) -> Option<usize> {
+        self.channels[channel]
+            .channel
+            .sidechain
+            .filter(|&x| x < self.channels.len() && x != channel)
+    }
+    /// Returns for every channel how many sidechain sources have to be rendered before it.
+    /// Channels in a cycle, which `set_sidechain` prevents, get a silent sidechain signal.
+    fn sidechain_depths(&self) -> Vec<usize> {
+        let count = self.channels.len();
+        (0..count)
+            .map(|i| {
+                let mut depth = 0;
+                let mut current = i;
+                while let Some(source) = self.sidechain_source(current) {
+                    depth += 1;
+                    current = source;
+                    if depth > count {
+                        break;
+                    }
+                }
+                depth
+            })
+            .collect()
+    }
+    /// Sorts the channels for `tick_signals`, so that sidechain sources are ticked first.
+    fn update_sidechain_order(&mut self) {
+        let depths = self.sidechain_depths();
+        self.sidechain_order = (0..self.channels.len()).collect();
+        self.sidechain_order.sort_by_key(|&i| depths[i]);
+    }
+    /// Returns which channels have to be rendered: the audible ones and the sidechain sources
+    /// of rendered channels, buses and the master.
+    fn rendered_channels(&self) -> Vec<bool> {
+        let count = self.channels.len();
+        let mut rendered: Vec<bool> = (0..count).map(|i| self.is_audible(i)).collect();
+        let mut pending: Vec<usize> = self
+            .buses
+            .iter()
+            .chain(std::iter::once(&self.master))
+            .filter_map(|x| x.sidechain)
+            .chain(
+                (0..count)
+                    .filter(|&i| rendered[i])
+                    .filter_map(|i| self.sidechain_source(i)),
+            )
             .collect();
-        self.time += self.delta_time;
-        output
+        while let Some(i) = pending.pop() {
+            if i < count && !rendered[i] {
+                rendered[i] = true;
+                pending.extend(self.sidechain_source(i));
+            }
+        }
+        rendered
+    }
+    /// Returns whether a channel is heard, taking mute and solo of all channels into account.
+    pub fn is_audible(&self, channel: usize) -> bool {
+        let solo = self.channels.iter().any(|x| x.channel.solo);
+        let channel = &self.channels[channel].channel;
+        !channel.mute && (channel.solo || !solo)
+    }
+    fn audible_channels(&self) -> Vec<bool> {
+        let mut audible = Vec::new();
+        self.fill_audible(&mut audible);
+        audible
+    }
+    /// Like `audible_channels`, but reuses a buffer.
+    fn fill_audible(&self, audible: &mut Vec<bool>) {
+        let solo = self.channels.iter().any(|x| x.channel.solo);
+        audible.clear();
+        audible.extend(
+            self.channels
+                .iter()
+                .map(|x| !x.channel.mute && (x.channel.solo || !solo)),
+        );
+    }
+    /// Ticks all channels and returns their outputs. Channels that are not audible are still ticked,
+    /// so that they can be unmuted during playback, but their output is silent.
+    pub fn tick_channels(&mut self) -> Vec<Frame<f64, U2>> {
+        let audible = self.audible_channels();
+        self.tick_signals();
+        self.signals
+            .iter()
+            .zip(audible)
+            .map(|(&(output, _), audible)| if audible { output } else { [0.0, 0.0].into() })
+            .collect()
+    }
+    /// Ticks all channels and stores their outputs and pre-fader signals in `signals`, including those of
+    /// channels that are not audible, which may still be sidechain sources.
+    fn tick_signals(&mut self) {
+        let sample = self.sample_index;
+        if self.sidechain_order.len() != self.channels.len() {
+            self.update_sidechain_order();
+        }
+        let silence: Frame<f64, U2> = [0.0, 0.0].into();
+        self.signals.clear();
+        self.signals.resize(self.channels.len(), (silence, silence));
+        for k in 0..self.sidechain_order.len() {
+            let i = self.sidechain_order[k];
+            let key = self
+                .sidechain_source(i)
+                .map(|source| (self.signals[source].0[0], self.signals[source].0[1]));
+            self.signals[i] = self.channels[i].tick(sample, key);
+        }
+        self.sample_index += 1;
+    }
+    /// Returns the gain reduction of the master processors during realtime playback since the last
+    /// reset, like `RenderReport::gain_reduction`.
+    pub fn gain_reduction(&self) -> &[f64] {
+        self.mixer.as_ref().map_or(&[], |x| &x.gain_reduction)
+    }
+}
+
+impl Default for DAW {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -148,33 +450,88 @@ impl AudioNode for DAW {
     type Outputs = U2;
     type Setting = ();
 
+    /// Mixes the channels through the buses and the master chain, like the renders.
     fn tick(
         &mut self,
         _input: &Frame<Self::Sample, Self::Inputs>,
     ) -> Frame<Self::Sample, Self::Outputs> {
-        self.tick_channels()
-            .into_iter()
-            .reduce(|a, b| a + b)
-            .unwrap()
+        let time = self.sample_index as f64 / self.sample_rate;
+        self.tick_signals();
+        // The buffers are reused, so that playback doesn't allocate on the audio thread.
+        let signals = std::mem::take(&mut self.signals);
+        let mut mixer = match self.mixer.take() {
+            Some(mixer) if mixer.matches(self) => mixer,
+            _ => Mixer::new(self),
+        };
+        self.fill_audible(&mut mixer.audible);
+        let output = mixer.mix(self, time, |i| {
+            let (output, pre_fader) = signals[i];
+            ((output[0], output[1]), (pre_fader[0], pre_fader[1]))
+        });
+        self.mixer = Some(mixer);
+        self.signals = signals;
+        output
     }
 
     fn reset(&mut self) {
-        self.time = 0.0;
+        self.sample_index = 0;
+        self.mixer = None;
+        self.update_sidechain_order();
         for channel in self.channels.iter_mut() {
+            channel.channel.set_tempo_map(&self.tempo_map);
             channel.reset();
         }
+        for bus in self.buses.iter_mut() {
+            bus.set_tempo_map(&self.tempo_map);
+            bus.reset();
+        }
+        self.master.set_tempo_map(&self.tempo_map);
         self.master.reset();
     }
 
     fn set_sample_rate(&mut self, sample_rate: f64) {
-        self.delta_time = 1.0 / sample_rate;
+        self.sample_rate = sample_rate;
         for channel in self.channels.iter_mut() {
             channel.set_sample_rate(sample_rate);
         }
+        for bus in self.buses.iter_mut() {
+            bus.set_sample_rate(sample_rate);
+        }
         self.master.set_sample_rate(sample_rate);
     }
 }
 
+/// Where a channel or bus sends its output.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Output {
+    #[default]
+    Master,
+    Bus(usize),
+}
+
+/// Sends a copy of the signal of a channel or bus to a bus, e.g. a shared reverb return.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AuxSend {
+    pub bus: usize,
+    pub level: f64,
+    /// If set, the signal is taken before the volume, pan and processors, otherwise from the output.
+    pub pre_fader: bool,
+}
+
+impl AuxSend {
+    pub fn new(bus: usize, level: f64, pre_fader: bool) -> Self {
+        Self {
+            bus,
+            level,
+            pre_fader,
+        }
+    }
+}
+
+/// A mixer channel with volume, pan and a chain of processors.
+/// MIDI volume (CC7) and expression (CC11) scale the volume, and MIDI pan (CC10) overrides the pan.
+/// Use `DAW::route` and `DAW::add_send` to change `output` and `sends`, which check that the buses exist.
+/// Routes to buses that don't exist go to the master, and sends to them are ignored.
 #[derive(Clone)]
 pub struct Channel {
     pub index: usize,
@@ -182,6 +539,22 @@ pub struct Channel {
     pub pan: f64,
     pub processors: Vec<Box<dyn Processor>>,
     pub name: String,
+    pub output: Output,
+    pub sends: Vec<AuxSend>,
+    /// Silences the channel, including its pre-fader sends. Muted synth channels are not rendered at all.
+    pub mute: bool,
+    /// If any synth channel is soloed, only soloed channels are heard. Ignored for buses and the master.
+    pub solo: bool,
+    pub automation: Vec<AutomationLane>,
+    /// The tempo map of the DAW, which resolves the automation lanes added with `add_automation`.
+    tempo_map: TempoMap,
+    /// Set with `DAW::set_sidechain` or `DAW::set_bus_sidechain`, which check the source.
+    sidechain: Option<usize>,
+    midi_volume: f64,
+    expression: f64,
+    midi_pan: Option<f64>,
+    automated_volume: Option<f64>,
+    automated_pan: Option<f64>,
 }
 
 impl Channel {
@@ -198,24 +571,124 @@ impl Channel {
             pan,
             processors,
             name,
+            output: Output::Master,
+            sends: Vec::new(),
+            mute: false,
+            solo: false,
+            automation: Vec::new(),
+            tempo_map: TempoMap::default(),
+            sidechain: None,
+            midi_volume: 1.0,
+            expression: 1.0,
+            midi_pan: None,
+            automated_volume: None,
+            automated_pan: None,
         }
     }
     fn tick(&mut self, time: f64, input: &Frame<f64, U2>) -> Frame<f64, U2> {
+        if self.mute {
+            return [0.0, 0.0].into();
+        }
+        self.automate(time);
         let adjusted = self.volume_pan(input);
+        self.process(time, &adjusted)
+    }
+    fn process(&mut self, time: f64, input: &Frame<f64, U2>) -> Frame<f64, U2> {
         self.processors
             .iter_mut()
-            .fold(adjusted, |acc, x| x.tick(time, &acc))
+            .fold(*input, |acc, x| x.tick(time, &acc))
+    }
+    fn feed_sidechain(&mut self, key: &Frame<f64, U2>) {
+        for processor in self.processors.iter_mut() {
+            processor.sidechain(key);
+        }
+    }
+    /// Applies the automation lanes at a time in seconds.
+    fn automate(&mut self, time: f64) {
+        for lane in self.automation.iter() {
+            let Some(value) = lane.value_at(time) else {
+                continue;
+            };
+            match &lane.target {
+                AutomationTarget::Volume => self.automated_volume = Some(value),
+                AutomationTarget::Pan => self.automated_pan = Some(value.clamp(-1.0, 1.0)),
+                AutomationTarget::Parameter { processor, name } => {
+                    if let Some(processor) = self.processors.get_mut(*processor) {
+                        processor.set_parameter(name, value);
+                    }
+                }
+            }
+        }
+    }
+    /// Resolves the automation lanes and passes the tempo map to the processors.
+    fn set_tempo_map(&mut self, tempo_map: &TempoMap) {
+        for lane in self.automation.iter_mut() {
+            lane.resolve(tempo_map);
+        }
+        for processor in self.processors.iter_mut() {
+            processor.set_tempo_map(tempo_map);
+        }
+        self.tempo_map = tempo_map.clone();
     }
     fn volume_pan(&self, input: &Frame<f64, U2>) -> Frame<f64, U2> {
-        let left_vol = self.volume * (1.0 - self.pan).clamp(0.0, 1.0);
-        let right_vol = self.volume * (1.0 + self.pan).clamp(0.0, 1.0);
+        let volume =
+            self.automated_volume.unwrap_or(self.volume) * self.midi_volume * self.expression;
+        let pan = self.automated_pan.or(self.midi_pan).unwrap_or(self.pan);
+        let left_vol = volume * (1.0 - pan).clamp(0.0, 1.0);
+        let right_vol = volume * (1.0 + pan).clamp(0.0, 1.0);
         [left_vol * input[0], right_vol * input[1]].into()
     }
+    /// Applies a MIDI control change. Unsupported controllers are ignored.
+    pub fn control(&mut self, controller: u8, value: u8) {
+        let normalized = value as f64 / 127.0;
+        match controller {
+            CC_VOLUME => self.midi_volume = normalized * normalized,
+            CC_EXPRESSION => self.expression = normalized * normalized,
+            CC_PAN => self.midi_pan = Some(((value as f64 - 64.0) / 63.0).clamp(-1.0, 1.0)),
+            _ => (),
+        }
+    }
     pub fn add<T>(&mut self, processor: T)
     where
         T: Processor + 'static,
     {
-        self.processors.push(Box::new(processor))
+        let mut processor = Box::new(processor);
+        processor.set_tempo_map(&self.tempo_map);
+        self.processors.push(processor)
+    }
+    /// Adds an automation lane after checking that its processor and parameter exist.
+    pub fn add_automation(&mut self, mut lane: AutomationLane) -> Result<(), anyhow::Error> {
+        if let AutomationTarget::Parameter { processor, name } = &lane.target {
+            let Some(target) = self.processors.get(*processor) else {
+                anyhow::bail!(
+                    "Channel \"{}\" has no processor {} to automate.",
+                    self.name,
+                    processor
+                );
+            };
+            // The parameter is set on a copy, so that the processor keeps its state until the render.
+            let exists = |point: &Breakpoint| {
+                dyn_clone::clone_box(&**target).set_parameter(name, point.value)
+            };
+            anyhow::ensure!(
+                lane.points.first().map_or(true, exists),
+                "Processor {} of channel \"{}\" has no parameter \"{}\".",
+                processor,
+                self.name,
+                name
+            );
+        }
+        lane.resolve(&self.tempo_map);
+        self.automation.push(lane);
+        Ok(())
+    }
+    /// Returns the synth channel whose output is passed to `Processor::sidechain` of the processors.
+    pub fn sidechain(&self) -> Option<usize> {
+        self.sidechain
+    }
+    /// Returns by how many samples the processors delay the output, see `Processor::latency`.
+    pub fn latency(&self) -> usize {
+        self.processors.iter().map(|x| x.latency()).sum()
     }
     fn set_sample_rate(&mut self, sample_rate: f64) {
         for processor in self.processors.iter_mut() {
@@ -226,6 +699,11 @@ impl Channel {
         for processor in self.processors.iter_mut() {
             processor.reset();
         }
+        self.midi_volume = 1.0;
+        self.expression = 1.0;
+        self.midi_pan = None;
+        self.automated_volume = None;
+        self.automated_pan = None;
     }
 }
 
@@ -247,23 +725,109 @@ impl IndexMut<usize> for Channel {
 struct SynthChannel {
     channel: Channel,
     synth: Box<dyn Synthesizer>,
+    controls: MidiWrapper,
+    midi: Vec<MidiMsg>,
+    sample_rate: f64,
+    /// The taps of `tick`, which are reused for every sample.
+    taps: Taps,
 }
 
 impl SynthChannel {
     fn new(channel: Channel, synth: Box<dyn Synthesizer>) -> Self {
-        Self { channel, synth }
+        Self {
+            channel,
+            synth,
+            controls: MidiWrapper::new(Vec::new()),
+            midi: Vec::new(),
+            sample_rate: DEFAULT_SR,
+            taps: Taps::default(),
+        }
     }
-    fn tick(&mut self, time: f64) -> Frame<f64, U2> {
-        let input = self.synth.tick(time);
-        self.channel.tick(time, &input)
+    fn set_midi(&mut self, midi: Vec<MidiMsg>) {
+        let controls = midi
+            .iter()
+            .filter(|x| matches!(x.kind(), MsgType::Control(_, _)))
+            .copied()
+            .collect();
+        self.controls = MidiWrapper::new(controls);
+        self.controls.set_sample_rate(self.sample_rate);
+        self.synth.set_midi(midi.clone());
+        self.midi = midi;
+    }
+    fn set_instrument(&mut self, instrument: &dyn MidiInstrument) {
+        self.synth = instrument.build_synth();
+        self.synth.set_sample_rate(self.sample_rate);
+        self.synth.set_midi(self.midi.clone());
+        self.channel.processors = instrument.build_processors();
+        for processor in self.channel.processors.iter_mut() {
+            processor.set_sample_rate(self.sample_rate);
+            processor.set_tempo_map(&self.channel.tempo_map);
+        }
+    }
+    /// Returns the output and the pre-fader signal of a single sample.
+    fn tick(&mut self, sample: usize, key: Option<(f64, f64)>) -> (Frame<f64, U2>, Frame<f64, U2>) {
+        let mut output = [[0.0, 0.0].into()];
+        let mut taps = std::mem::take(&mut self.taps);
+        if taps.pre_fader.is_some() != self.has_pre_fader_sends() {
+            taps = Taps::new(self.has_pre_fader_sends(), false, 1);
+        }
+        taps.clear();
+        let key = key.as_ref().map_or(&[][..], std::slice::from_ref);
+        self.process(sample, &mut output, &mut taps, key);
+        let pre_fader = taps
+            .pre_fader
+            .as_ref()
+            .and_then(|x| x.first())
+            .map_or(output[0], |x| [x.0, x.1].into());
+        self.taps = taps;
+        (output[0], pre_fader)
+    }
+    /// Renders a block of samples and appends the signals to the taps that are set.
+    /// `key` is the sidechain signal for the block, which is silent where it is shorter than the block.
+    fn process(
+        &mut self,
+        start: usize,
+        output: &mut [Frame<f64, U2>],
+        taps: &mut Taps,
+        key: &[(f64, f64)],
+    ) {
+        self.synth.process(start, output);
+        for (i, frame) in output.iter_mut().enumerate() {
+            let sample = start + i;
+            while let Some(msg) = self.controls.pop(sample) {
+                if let MsgType::Control(controller, value) = msg {
+                    self.channel.control(controller, value);
+                }
+            }
+            let time = sample as f64 / self.sample_rate;
+            self.channel.automate(time);
+            if self.channel.sidechain.is_some() {
+                let key = key.get(i).copied().unwrap_or((0.0, 0.0));
+                self.channel.feed_sidechain(&[key.0, key.1].into());
+            }
+            if let Some(pre_fader) = taps.pre_fader.as_mut() {
+                pre_fader.push((frame[0], frame[1]));
+            }
+            let adjusted = self.channel.volume_pan(frame);
+            if let Some(dry) = taps.dry.as_mut() {
+                dry.push((adjusted[0], adjusted[1]));
+            }
+            *frame = self.channel.process(time, &adjusted);
+        }
+    }
+    fn has_pre_fader_sends(&self) -> bool {
+        self.channel.sends.iter().any(|x| x.pre_fader)
     }
     fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        self.controls.set_sample_rate(sample_rate);
         self.channel.set_sample_rate(sample_rate);
         self.synth.set_sample_rate(sample_rate);
     }
     fn reset(&mut self) {
         self.channel.reset();
         self.synth.reset();
+        self.controls.reset();
     }
 }
 
@@ -271,124 +835,1059 @@ impl SynthChannel {
 pub struct RenderedAudio {
     pub master: Vec<(f64, f64)>,
     pub channels: Vec<Vec<(f64, f64)>>,
+    /// The channels before their processors. Empty unless `DAW::record_dry` was set.
+    pub dry_channels: Vec<Vec<(f64, f64)>>,
+    pub names: Vec<String>,
+    /// Describes the render. Not saved, so it is empty after `load`.
+    #[serde(skip)]
+    pub report: RenderReport,
+}
+
+/// How stems are normalized when they are exported.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StemNormalization {
+    None,
+    /// Every stem is normalized on its own.
+    Individual,
+    /// All stems are scaled by the same gain, so that they stay balanced when mixed.
+    Shared,
+}
+
+/// How the master is normalized when it is converted to a wave.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Normalization {
+    None,
+    /// Scales the master so that its sample peak is at full scale. Nothing is clipped,
+    /// so the shape of the peaks set by a limiter at the end of the master chain is kept.
+    Peak,
+    /// Scales the master to an integrated loudness in LUFS.
+    /// The gain is reduced if the true peak would otherwise exceed `true_peak_ceiling` (in dBTP).
+    Loudness {
+        target: f64,
+        true_peak_ceiling: f64,
+    },
+}
+
+/// The layout of `RenderedAudio` before stems were added, which files without a header still use.
+#[derive(Deserialize)]
+struct LegacyRenderedAudio {
+    master: Vec<(f64, f64)>,
+    channels: Vec<Vec<(f64, f64)>>,
 }
 
 impl RenderedAudio {
+    /// Starts the files written by `save`, followed by the format version as little-endian `u32`.
+    const MAGIC: &'static [u8; 8] = b"SMRENDER";
+    const FORMAT_VERSION: u32 = 1;
+
     pub fn save<P>(&self, path: P) -> Result<(), anyhow::Error>
     where
         P: AsRef<std::path::Path>,
     {
-        let data = bincode::serialize(self)?;
+        let mut data = Self::MAGIC.to_vec();
+        data.extend(Self::FORMAT_VERSION.to_le_bytes());
+        data.extend(bincode::serialize(self)?);
         std::fs::write(path, data)?;
         Ok(())
     }
+    /// Loads a file written by `save`. Files of earlier versions without a header are loaded as well,
+    /// with the channels named by their index.
     pub fn load<P>(path: P) -> Result<Self, anyhow::Error>
     where
         P: AsRef<std::path::Path>,
     {
         let data = std::fs::read(path)?;
-        let audio = bincode::deserialize(&data)?;
+        let Some(data) = data.strip_prefix(Self::MAGIC) else {
+            let legacy: LegacyRenderedAudio = bincode::deserialize(&data)?;
+            return Ok(Self {
+                names: (0..legacy.channels.len())
+                    .map(|i| format!("Channel {}", i))
+                    .collect(),
+                master: legacy.master,
+                channels: legacy.channels,
+                dry_channels: Vec::new(),
+                report: RenderReport::default(),
+            });
+        };
+        anyhow::ensure!(data.len() >= 4, "The rendered audio file is truncated.");
+        let (version, data) = data.split_at(4);
+        let version = u32::from_le_bytes(version.try_into()?);
+        anyhow::ensure!(
+            version == Self::FORMAT_VERSION,
+            "Unsupported rendered audio format version {}, expected {}.",
+            version,
+            Self::FORMAT_VERSION
+        );
+        let audio = bincode::deserialize(data)?;
         Ok(audio)
     }
-    pub fn master_wave(self, sample_rate: f64, normalize: bool) -> Wave64 {
-        let mut wave = Wave64::new(0, sample_rate);
-        let (left_channel, right_channel): (Vec<f64>, Vec<f64>) = self.master.into_iter().unzip();
+    pub fn master_wave(self, sample_rate: f64, normalization: Normalization) -> Wave64 {
+        self.master_wave_with_report(sample_rate, normalization).0
+    }
+    /// Like `master_wave`, but also returns the gain that was applied and the levels before normalization.
+    pub fn master_wave_with_report(
+        &self,
+        sample_rate: f64,
+        normalization: Normalization,
+    ) -> (Wave64, NormalizationReport) {
+        let mut wave = Self::to_wave(&self.master, sample_rate);
+        let mut report = NormalizationReport {
+            gain: 1.0,
+            peak: Self::sample_peak(&wave),
+            loudness: None,
+            clipped: 0,
+        };
+        // The wave is divided by the peak itself, so that no sample ends up above full scale by rounding.
+        let mut divisor = 1.0;
+        match normalization {
+            Normalization::None => (),
+            Normalization::Peak => {
+                if report.peak > 0.0 {
+                    divisor = report.peak;
+                    report.gain = 1.0 / report.peak;
+                }
+            }
+            Normalization::Loudness {
+                target,
+                true_peak_ceiling,
+            } => {
+                let loudness = Loudness::measure(&wave);
+                if loudness.integrated.is_finite() {
+                    report.gain = db_amp(target - loudness.integrated)
+                        .min(db_amp(true_peak_ceiling) / loudness.true_peak);
+                    divisor = 1.0 / report.gain;
+                }
+                report.loudness = Some(loudness);
+            }
+        }
+        if normalization != Normalization::None {
+            report.clipped = Self::scale(&mut wave, divisor);
+        }
 
-        wave.push_channel(&left_channel);
-        wave.push_channel(&right_channel);
-        if normalize {
-            let max = Self::determine_amplitude(&wave, 5);
-            println!("Max amplitude: {}", max);
+        (wave, report)
+    }
+    /// Returns the name and wave of every channel, either with or without its processors.
+    /// All stems have the length of the master, and those of channels that are not heard are silent.
+    pub fn stem_waves(
+        &self,
+        sample_rate: f64,
+        processed: bool,
+        normalization: StemNormalization,
+    ) -> Result<Vec<(String, Wave64)>, anyhow::Error> {
+        let channels = if processed {
+            &self.channels
+        } else if self.dry_channels.len() == self.channels.len() {
+            &self.dry_channels
+        } else {
+            anyhow::bail!("Dry stems were not recorded, set DAW::record_dry before rendering.");
+        };
+
+        let mut waves: Vec<Wave64> = channels
+            .iter()
+            .map(|x| Self::to_wave(x, sample_rate))
+            .collect();
+        let amplitudes: Vec<f64> = waves
+            .iter()
+            .map(|x| Self::determine_amplitude(x, 5))
+            .collect();
+        let shared = amplitudes.iter().copied().fold(0.0, max);
+        for (wave, amplitude) in waves.iter_mut().zip(amplitudes) {
+            match normalization {
+                StemNormalization::None => (),
+                StemNormalization::Individual if amplitude > 0.0 => {
+                    Self::scale(wave, amplitude);
+                }
+                StemNormalization::Individual => (),
+                StemNormalization::Shared if shared > 0.0 => {
+                    Self::scale(wave, shared);
+                }
+                StemNormalization::Shared => (),
+            }
+        }
 
-            wave.channels_mut()
-                .iter_mut()
-                .for_each(|x| x.iter_mut().for_each(|y| *y = (*y / max).clamp(-1.0, 1.0)));
+        Ok(self.names.iter().cloned().zip(waves).collect())
+    }
+    /// Writes one WAV file per channel into `dir`, named after the channel.
+    /// Returns the path and the number of clipped samples of every stem.
+    pub fn save_stems<P>(
+        &self,
+        dir: P,
+        sample_rate: f64,
+        processed: bool,
+        normalization: StemNormalization,
+        options: &WavOptions,
+    ) -> Result<Vec<(std::path::PathBuf, usize)>, anyhow::Error>
+    where
+        P: AsRef<std::path::Path>,
+    {
+        std::fs::create_dir_all(&dir)?;
+        let mut paths = Vec::new();
+        for (i, (name, wave)) in self
+            .stem_waves(sample_rate, processed, normalization)?
+            .into_iter()
+            .enumerate()
+        {
+            let path = stem_path(dir.as_ref(), i, &name);
+            let clipped = save_wav(&wave, &path, options)?;
+            paths.push((path, clipped));
         }
+        Ok(paths)
+    }
+    fn to_wave(samples: &[(f64, f64)], sample_rate: f64) -> Wave64 {
+        let mut wave = Wave64::new(0, sample_rate);
+        let (left_channel, right_channel): (Vec<f64>, Vec<f64>) = samples.iter().copied().unzip();
 
+        wave.push_channel(&left_channel);
+        wave.push_channel(&right_channel);
         wave
     }
+    /// Divides the wave by `max` and clamps it to full scale. Returns the number of clamped samples.
+    fn scale(wave: &mut Wave64, max: f64) -> usize {
+        let mut clipped = 0;
+        for channel in wave.channels_mut().iter_mut() {
+            for x in channel.iter_mut() {
+                *x /= max;
+                if abs(*x) > 1.0 {
+                    clipped += 1;
+                    *x = x.clamp(-1.0, 1.0);
+                }
+            }
+        }
+        clipped
+    }
+    /// Returns the largest absolute sample value.
+    fn sample_peak(wave: &Wave64) -> f64 {
+        (0..wave.channels())
+            .flat_map(|channel| (0..wave.len()).map(move |i| abs(wave.at(channel, i))))
+            .fold(0.0, max)
+    }
+    /// Returns the largest moving average of the absolute sample values over `window_size` samples.
     fn determine_amplitude(wave: &Wave64, window_size: usize) -> f64 {
         let mut peak = 0.0;
         for channel in 0..wave.channels() {
-            for window in (0..wave.len()).collect::<Vec<usize>>().windows(window_size) {
-                let avg = window
-                    .iter()
-                    .map(|i| abs(wave.at(channel, *i)))
-                    .sum::<f64>()
-                    / window.len() as f64;
-                peak = max(peak, avg);
+            let mut sum = 0.0;
+            for i in 0..wave.len() {
+                sum += abs(wave.at(channel, i));
+                if i >= window_size {
+                    sum -= abs(wave.at(channel, i - window_size));
+                }
+                if i + 1 >= window_size {
+                    peak = max(peak, sum / window_size as f64);
+                }
             }
         }
         peak
     }
 }
 
+type Samples = Vec<(f64, f64)>;
+
+/// Recordings of the signal of a channel before its processors.
+#[derive(Clone, Default)]
+struct Taps {
+    /// The synthesizer output, before volume and pan, for pre-fader sends.
+    pre_fader: Option<Samples>,
+    /// The signal after volume and pan, for dry stems.
+    dry: Option<Samples>,
+}
+
+impl Taps {
+    fn new(pre_fader: bool, dry: bool, capacity: usize) -> Self {
+        Self {
+            pre_fader: pre_fader.then(|| Vec::with_capacity(capacity)),
+            dry: dry.then(|| Vec::with_capacity(capacity)),
+        }
+    }
+    fn clear(&mut self) {
+        for samples in [&mut self.pre_fader, &mut self.dry].into_iter().flatten() {
+            samples.clear();
+        }
+    }
+}
+
+/// Delays a signal by a fixed number of samples.
+#[derive(Clone)]
+struct Compensation(VecDeque<Frame<f64, U2>>);
+
+impl Compensation {
+    fn new(length: usize) -> Self {
+        Self(vec![[0.0, 0.0].into(); length].into())
+    }
+    fn tick(&mut self, input: &Frame<f64, U2>) -> Frame<f64, U2> {
+        if self.0.is_empty() {
+            return *input;
+        }
+        let output = self.0.pop_front().unwrap_or_default();
+        self.0.push_back(*input);
+        output
+    }
+}
+
+/// Mixes the audible channels through the buses into the master, one sample at a time.
+/// The outputs and sends of channels and buses are delayed so that all inputs of a bus or the master
+/// have the same latency, see `Processor::latency`.
+#[derive(Clone)]
+struct Mixer {
+    bus_inputs: Vec<Frame<f64, U2>>,
+    audible: Vec<bool>,
+    /// The delays of the output and of every send of the channels, followed by those of the buses.
+    compensation: Vec<Vec<Compensation>>,
+    /// The latency of the master output in samples.
+    latency: usize,
+    /// See `RenderReport::gain_reduction`.
+    gain_reduction: Vec<f64>,
+    mixed: usize,
+}
+
+impl Mixer {
+    fn new(daw: &DAW) -> Self {
+        let buses = daw.buses.len();
+        // The latency with which the inputs of every bus and, last, of the master arrive.
+        let mut arrival = vec![0; buses + 1];
+        let mut paths = Vec::new();
+        for channel in daw.channels.iter() {
+            let channel_paths = Self::paths(&channel.channel, 0, None, buses);
+            Self::update_arrival(&mut arrival, &channel_paths);
+            paths.push(channel_paths);
+        }
+        for (i, bus) in daw.buses.iter().enumerate() {
+            // All inputs of a bus come from channels and earlier buses, so its latency is final here.
+            let bus_paths = Self::paths(bus, arrival[i], Some(i), buses);
+            Self::update_arrival(&mut arrival, &bus_paths);
+            paths.push(bus_paths);
+        }
+        let compensation = paths
+            .iter()
+            .map(|x| {
+                x.iter()
+                    .map(|&(destination, latency)| {
+                        Compensation::new(destination.map_or(0, |y| arrival[y] - latency))
+                    })
+                    .collect()
+            })
+            .collect();
+        Self {
+            bus_inputs: vec![[0.0, 0.0].into(); buses],
+            audible: (0..daw.channels.len()).map(|i| daw.is_audible(i)).collect(),
+            compensation,
+            latency: arrival[buses] + daw.master.latency(),
+            gain_reduction: Vec::new(),
+            mixed: 0,
+        }
+    }
+    /// Returns whether the mixer was created for the current buses and sends of the DAW.
+    fn matches(&self, daw: &DAW) -> bool {
+        let channels = daw.channels.iter().map(|x| &x.channel);
+        self.bus_inputs.len() == daw.buses.len()
+            && self.compensation.len() == daw.channels.len() + daw.buses.len()
+            && self
+                .compensation
+                .iter()
+                .zip(channels.chain(daw.buses.iter()))
+                .all(|(x, channel)| x.len() == channel.sends.len() + 1)
+    }
+    /// Returns the destination and the latency of the output and of every send of a channel or of bus
+    /// `source`, whose input has a latency of `input` samples. The master is destination `buses`,
+    /// and sends to buses that don't exist have none.
+    fn paths(
+        channel: &Channel,
+        input: usize,
+        source: Option<usize>,
+        buses: usize,
+    ) -> Vec<(Option<usize>, usize)> {
+        let output = input + channel.latency();
+        let destination = match channel.output {
+            Output::Bus(bus) if Self::is_valid(bus, buses, source) => bus,
+            _ => buses,
+        };
+        std::iter::once((Some(destination), output))
+            .chain(channel.sends.iter().map(|x| {
+                let latency = if x.pre_fader { input } else { output };
+                (
+                    Self::is_valid(x.bus, buses, source).then_some(x.bus),
+                    latency,
+                )
+            }))
+            .collect()
+    }
+    fn update_arrival(arrival: &mut [usize], paths: &[(Option<usize>, usize)]) {
+        for &(destination, latency) in paths {
+            if let Some(destination) = destination {
+                arrival[destination] = max(arrival[destination], latency);
+            }
+        }
+    }
+    /// Returns whether a channel or bus `source` can be routed or send into a bus.
+    fn is_valid(bus: usize, buses: usize, source: Option<usize>) -> bool {
+        bus < buses && source.map_or(true, |x| x < bus)
+    }
+    /// Returns the master output. `signal` returns the output and the pre-fader signal of a channel,
+    /// and is also used for the sidechain signals of the buses and the master.
+    fn mix<F>(&mut self, daw: &mut DAW, time: f64, signal: F) -> Frame<f64, U2>
+    where
+        F: Fn(usize) -> ((f64, f64), (f64, f64)),
+    {
+        let inputs = &mut self.bus_inputs;
+        inputs.iter_mut().for_each(|x| *x = [0.0, 0.0].into());
+        let mut master: Frame<f64, U2> = [0.0, 0.0].into();
+
+        let (channel_delays, bus_delays) = self.compensation.split_at_mut(daw.channels.len());
+        for (i, (channel, delays)) in daw.channels.iter().zip(channel_delays).enumerate() {
+            if !self.audible[i] {
+                continue;
+            }
+            let (output, pre_fader) = signal(i);
+            let output: Frame<f64, U2> = [output.0, output.1].into();
+            let pre_fader: Frame<f64, U2> = [pre_fader.0, pre_fader.1].into();
+            Self::route(
+                inputs,
+                &mut master,
+                &channel.channel,
+                delays,
+                &output,
+                &pre_fader,
+                None,
+            );
+        }
+        let count = daw.channels.len();
+        for bus in daw.buses.iter_mut().chain(std::iter::once(&mut daw.master)) {
+            if let Some(source) = bus.sidechain.filter(|&x| x < count) {
+                let (key, _) = signal(source);
+                bus.feed_sidechain(&[key.0, key.1].into());
+            }
+        }
+        for (i, (bus, delays)) in daw.buses.iter_mut().zip(bus_delays).enumerate() {
+            // Like muted synth channels, muted buses don't send their pre-fader signal either.
+            if bus.mute {
+                continue;
+            }
+            let input = inputs[i];
+            let output = bus.tick(time, &input);
+            Self::route(inputs, &mut master, bus, delays, &output, &input, Some(i));
+        }
+        let output = daw.master.tick(time, &master);
+        self.track_reduction(&daw.master);
+        output
+    }
+    /// Records the gain reduction of the master processors, keeping the largest one of every block.
+    fn track_reduction(&mut self, master: &Channel) {
+        let reduction = master
+            .processors
+            .iter()
+            .filter_map(|x| x.gain_reduction())
+            .reduce(|a, b| a + b);
+        if let Some(reduction) = reduction {
+            match self.gain_reduction.last_mut() {
+                Some(last) if self.mixed % BLOCK_SIZE != 0 => *last = min(*last, reduction),
+                _ => self.gain_reduction.push(reduction),
+            }
+        }
+        self.mixed += 1;
+    }
+    /// Adds the output of a channel or of bus `source` to its destination, and its sends to their buses,
+    /// each delayed by its compensation.
+    fn route(
+        inputs: &mut [Frame<f64, U2>],
+        master: &mut Frame<f64, U2>,
+        channel: &Channel,
+        delays: &mut [Compensation],
+        output: &Frame<f64, U2>,
+        pre_fader: &Frame<f64, U2>,
+        source: Option<usize>,
+    ) {
+        let buses = inputs.len();
+        let Some((output_delay, send_delays)) = delays.split_first_mut() else {
+            return;
+        };
+        let delayed = output_delay.tick(output);
+        match channel.output {
+            Output::Bus(bus) if Self::is_valid(bus, buses, source) => inputs[bus] += delayed,
+            _ => *master += delayed,
+        }
+        for (send, delay) in channel.sends.iter().zip(send_delays) {
+            if Self::is_valid(send.bus, buses, source) {
+                let signal = if send.pre_fader { pre_fader } else { output };
+                inputs[send.bus] += delay.tick(signal) * Frame::splat(send.level);
+            }
+        }
+    }
+}
+
+/// Returns the path of the stem of a channel, made up of its index and its name without special characters.
+fn stem_path(dir: &Path, index: usize, name: &str) -> PathBuf {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || " -_".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    dir.join(format!("{:02} {}.wav", index, name.trim()))
+}
+
+/// Controls how long `render_daw` keeps rendering after the last MIDI message.
+/// Rendering stops once every channel and the master have stayed below `threshold` for `window`,
+/// but never continues for more than `max_length` after the end of the song.
+#[derive(Clone, Debug)]
+pub struct TailSettings {
+    pub threshold: f64,
+    pub window: Duration,
+    pub max_length: Duration,
+}
+
+impl TailSettings {
+    pub fn new(threshold: f64, window: Duration, max_length: Duration) -> Self {
+        Self {
+            threshold,
+            window,
+            max_length,
+        }
+    }
+    fn is_silent(&self, sample: (f64, f64)) -> bool {
+        abs(sample.0) <= self.threshold && abs(sample.1) <= self.threshold
+    }
+}
+
+impl Default for TailSettings {
+    /// Stops after half a second below -80 dB, or 30 seconds after the song.
+    fn default() -> Self {
+        Self::new(
+            1e-4,
+            Duration::from_secs_f64(0.5),
+            Duration::from_secs_f64(30.0),
+        )
+    }
+}
+
+/// Progress of a render in samples of the song.
+/// `expected` is the length of the song without its tail, so `rendered` exceeds it while the tail is rendered.
+#[derive(Clone, Copy, Debug)]
+pub struct RenderProgress {
+    /// The channel this progress belongs to, or `None` for the progress of the whole render.
+    pub channel: Option<usize>,
+    pub rendered: usize,
+    pub expected: usize,
+}
+
+impl RenderProgress {
+    /// Returns the progress as a fraction between 0 and 1.
+    pub fn fraction(&self) -> f64 {
+        if self.expected == 0 {
+            1.0
+        } else {
+            (self.rendered as f64 / self.expected as f64).min(1.0)
+        }
+    }
+}
+
+/// Cancels a render from another thread. Clones share the same flag.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The error returned by a render that was cancelled through its `CancellationToken`.
+#[derive(Clone, Copy, Debug)]
+pub struct RenderCancelled;
+
+impl std::fmt::Display for RenderCancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The render was cancelled.")
+    }
+}
+
+impl std::error::Error for RenderCancelled {}
+
+type ProgressCallback = Arc<dyn Fn(RenderProgress) + Send + Sync>;
+
+/// Observes and controls a render. The progress callback is called after every block,
+/// from the threads that render the channels, so it should return quickly.
+#[derive(Clone, Default)]
+pub struct RenderControl {
+    progress: Option<ProgressCallback>,
+    cancel: CancellationToken,
+}
+
+impl RenderControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_progress<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(RenderProgress) + Send + Sync + 'static,
+    {
+        self.progress = Some(Arc::new(callback));
+        self
+    }
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancel = token;
+        self
+    }
+    fn report(&self, channel: Option<usize>, rendered: usize, expected: usize) {
+        if let Some(progress) = &self.progress {
+            progress(RenderProgress {
+                channel,
+                rendered,
+                expected,
+            });
+        }
+    }
+    fn check(&self) -> Result<(), RenderCancelled> {
+        if self.cancel.is_cancelled() {
+            Err(RenderCancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
 pub fn render_daw(daw: &mut DAW, sample_rate: f64) -> RenderedAudio {
+    render_daw_with(daw, sample_rate, &RenderControl::new())
+        .expect("a render without a cancellation token can't be cancelled")
+}
+
+/// Like `render_daw`, but reports progress and can be cancelled, in which case `RenderCancelled` is returned.
+pub fn render_daw_with(
+    daw: &mut DAW,
+    sample_rate: f64,
+    control: &RenderControl,
+) -> Result<RenderedAudio, RenderCancelled> {
+    daw.set_sample_rate(sample_rate);
+    daw.reset();
+    let start_time = Instant::now();
+    let sample_count = (daw.duration.as_secs_f64() * sample_rate).round() as usize;
+    let tail = &daw.tail;
+    let record_dry = daw.record_dry;
+    let progress = ProgressTracker::new(control, daw.channels.len() + 1, sample_count);
+    let rendered = daw.rendered_channels();
+    let depths = daw.sidechain_depths();
+    // Channels are rendered in parallel, but sidechain sources before the channels that use them.
+    let mut results: Vec<Option<(Samples, Taps)>> = vec![None; daw.channels.len()];
+    for depth in 0..=depths.iter().copied().max().unwrap_or_default() {
+        let level = daw
+            .channels
+            .par_iter_mut()
+            .enumerate()
+            .filter(|(i, _)| depths[*i] == depth)
+            .map(|(i, x)| {
+                if !rendered[i] {
+                    progress.channel(i, sample_count, sample_count);
+                    return Ok((i, (Vec::new(), Taps::new(false, record_dry, 0))));
+                }
+                let key = x
+                    .channel
+                    .sidechain
+                    .and_then(|source| results.get(source)?.as_ref())
+                    .map_or(&[][..], |(samples, _)| &samples[..]);
+                let result = render_channel(x, sample_count, tail, record_dry, key, i, &progress)?;
+                Ok((i, result))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        for (i, result) in level {
+            results[i] = Some(result);
+        }
+    }
+    let (mut channels, mut taps): (Vec<Samples>, Vec<Taps>) =
+        results.into_iter().map(Option::unwrap_or_default).unzip();
+    let names = daw
+        .channels
+        .iter()
+        .map(|x| x.channel.name.clone())
+        .collect();
+
+    let (master, gain_reduction) =
+        render_master(daw, &channels, &taps, sample_count, sample_rate, &progress)?;
+    // Muted sidechain sources were only rendered for their signal. The latency of the processors is
+    // removed from the stems, like from the master, and all stems have the length of the master,
+    // so that they line up.
+    let audible = daw.audible_channels();
+    for (((samples, taps), audible), channel) in channels
+        .iter_mut()
+        .zip(taps.iter_mut())
+        .zip(audible)
+        .zip(daw.channels.iter())
+    {
+        samples.drain(..min(channel.channel.latency(), samples.len()));
+        for samples in std::iter::once(samples).chain(taps.dry.as_mut()) {
+            if !audible {
+                samples.clear();
+            }
+            samples.resize(master.len(), (0.0, 0.0));
+        }
+    }
+    let dry_channels = taps.iter_mut().filter_map(|x| x.dry.take()).collect();
+    let peak = master
+        .iter()
+        .fold(0.0, |acc, x| max(acc, max(abs(x.0), abs(x.1))));
+    let report = RenderReport {
+        song_duration: daw.duration,
+        length: master.len(),
+        render_time: start_time.elapsed(),
+        peak,
+        clipped: master
+            .iter()
+            .map(|x| (abs(x.0) > 1.0) as usize + (abs(x.1) > 1.0) as usize)
+            .sum(),
+        gain_reduction,
+    };
+    log_to(&daw.logger, || report.summary());
+    Ok(RenderedAudio {
+        master,
+        channels,
+        dry_channels,
+        names,
+        report,
+    })
+}
+
+/// Tracks the progress of `render_daw_with`, in which the channels and the master each count
+/// as one pass over the song for the overall progress.
+struct ProgressTracker<'a> {
+    control: &'a RenderControl,
+    rendered: AtomicUsize,
+    passes: usize,
+    sample_count: usize,
+}
+
+impl<'a> ProgressTracker<'a> {
+    fn new(control: &'a RenderControl, passes: usize, sample_count: usize) -> Self {
+        Self {
+            control,
+            rendered: AtomicUsize::new(0),
+            passes,
+            sample_count,
+        }
+    }
+    fn check(&self) -> Result<(), RenderCancelled> {
+        self.control.check()
+    }
+    /// Reports the progress of a channel, after it has rendered `samples` more samples.
+    fn channel(&self, index: usize, rendered: usize, samples: usize) {
+        self.control
+            .report(Some(index), rendered, self.sample_count);
+        self.add(samples);
+    }
+    fn add(&self, samples: usize) {
+        let rendered = self.rendered.fetch_add(samples, Ordering::Relaxed) + samples;
+        self.control
+            .report(None, rendered / self.passes, self.sample_count);
+    }
+}
+
+/// Renders the DAW block by block and writes the master, and optionally the stems, while rendering.
+/// Unlike `render_daw`, memory use doesn't grow with the length of the song, but the output isn't normalized.
+/// `stems` must either be empty or contain one writer per channel. They receive the processed signal of
+/// their channel, or the signal before its processors if `processed` is false.
+/// Rendering stops once all channels and the master have been silent for the tail window.
+/// Like in `render_daw`, the latency of the processors is removed, so all files have the same length.
+pub fn render_daw_to<W>(
+    daw: &mut DAW,
+    sample_rate: f64,
+    master: &mut WavWriter<W>,
+    stems: &mut [WavWriter<W>],
+    processed: bool,
+    control: &RenderControl,
+) -> Result<RenderReport, anyhow::Error>
+where
+    W: Write + Seek,
+{
+    anyhow::ensure!(
+        stems.is_empty() || stems.len() == daw.channels.len(),
+        "Expected one stem writer per channel ({}), got {}.",
+        daw.channels.len(),
+        stems.len()
+    );
     daw.set_sample_rate(sample_rate);
     daw.reset();
     let start_time = Instant::now();
-    println!("Started rendering...");
     let sample_count = (daw.duration.as_secs_f64() * sample_rate).round() as usize;
-    let channels: Vec<Vec<(f64, f64)>> = daw
+    let max_count = sample_count + (daw.tail.max_length.as_secs_f64() * sample_rate) as usize;
+    let window = (daw.tail.window.as_secs_f64() * sample_rate) as usize;
+    let record_dry = !processed && !stems.is_empty();
+    let mut blocks: Vec<Vec<Frame<f64, U2>>> =
+        vec![vec![[0.0, 0.0].into(); BLOCK_SIZE]; daw.channels.len()];
+    let audible: Vec<bool> = (0..daw.channels.len()).map(|i| daw.is_audible(i)).collect();
+    let mut taps: Vec<Taps> = daw
         .channels
-        .par_iter_mut()
+        .iter()
+        .zip(audible.iter())
+        .map(|(x, audible)| {
+            Taps::new(
+                *audible && x.has_pre_fader_sends(),
+                *audible && record_dry,
+                BLOCK_SIZE,
+            )
+        })
+        .collect();
+    let rendered = daw.rendered_channels();
+    let depths = daw.sidechain_depths();
+    let sources: Vec<Option<usize>> = (0..daw.channels.len())
+        .map(|i| daw.sidechain_source(i))
+        .collect();
+    let mut mixer = Mixer::new(daw);
+    // The stems are delayed to the latency of the master, which is then skipped in all files.
+    let mut stem_delays: Vec<Compensation> = daw
+        .channels
+        .iter()
+        .take(stems.len())
         .map(|x| {
-            let render = render_channel(x, sample_count, sample_rate);
-            render
+            let latency = if processed { x.channel.latency() } else { 0 };
+            Compensation::new(mixer.latency.saturating_sub(latency))
         })
         .collect();
+    let mut silent = 0;
+    let mut peak = 0.0;
 
-    let master = render_master(&mut daw.master, &channels, sample_count, sample_rate);
-    println!(
-        "Finished rendering in {:.2} seconds.",
-        start_time.elapsed().as_secs_f64()
-    );
-    RenderedAudio { master, channels }
+    for start in (0..max_count).step_by(BLOCK_SIZE) {
+        if start >= sample_count && silent >= window {
+            break;
+        }
+        control.check()?;
+        let length = min(BLOCK_SIZE, max_count - start);
+        // Sidechain sources are rendered before the channels that use them.
+        for depth in 0..=depths.iter().copied().max().unwrap_or_default() {
+            let keys: Vec<Samples> = sources
+                .iter()
+                .enumerate()
+                .map(|(i, source)| match source {
+                    Some(source) if depths[i] == depth => blocks[*source][..length]
+                        .iter()
+                        .map(|x| (x[0], x[1]))
+                        .collect(),
+                    _ => Vec::new(),
+                })
+                .collect();
+            daw.channels
+                .par_iter_mut()
+                .zip(blocks.par_iter_mut())
+                .zip(taps.par_iter_mut())
+                .zip(keys.par_iter())
+                .enumerate()
+                .filter(|(i, _)| depths[*i] == depth)
+                .for_each(|(i, (((channel, block), taps), key))| {
+                    taps.clear();
+                    if rendered[i] {
+                        channel.process(start, &mut block[..length], taps, key);
+                    } else {
+                        block.fill([0.0, 0.0].into());
+                    }
+                });
+        }
+        for i in 0..length {
+            let time = (start + i) as f64 / sample_rate;
+            let output = mixer.mix(daw, time, |j| {
+                let output = (blocks[j][i][0], blocks[j][i][1]);
+                (output, taps[j].pre_fader.as_ref().map_or(output, |x| x[i]))
+            });
+            let skip = start + i < mixer.latency;
+            if !skip {
+                master.write_frame(&[output[0], output[1]])?;
+                peak = max(peak, max(abs(output[0]), abs(output[1])));
+            }
+            for (j, (stem, delay)) in stems.iter_mut().zip(stem_delays.iter_mut()).enumerate() {
+                let sample = match &taps[j].dry {
+                    _ if !audible[j] => (0.0, 0.0),
+                    Some(dry) => dry[i],
+                    None => (blocks[j][i][0], blocks[j][i][1]),
+                };
+                let sample = delay.tick(&[sample.0, sample.1].into());
+                if !skip {
+                    stem.write_frame(&[sample[0], sample[1]])?;
+                }
+            }
+            let all_silent = daw.tail.is_silent((output[0], output[1]))
+                && blocks
+                    .iter()
+                    .zip(audible.iter())
+                    .all(|(x, audible)| !audible || daw.tail.is_silent((x[i][0], x[i][1])));
+            silent = if start + i >= sample_count && all_silent {
+                silent + 1
+            } else {
+                0
+            };
+        }
+        for i in 0..blocks.len() {
+            control.report(Some(i), start + length, sample_count);
+        }
+        control.report(None, start + length, sample_count);
+    }
+
+    let report = RenderReport {
+        song_duration: daw.duration,
+        length: master.len(),
+        render_time: start_time.elapsed(),
+        peak,
+        clipped: master.clipped(),
+        gain_reduction: mixer.gain_reduction,
+    };
+    log_to(&daw.logger, || report.summary());
+    Ok(report)
+}
+
+/// Streams the master into a WAV file and, if `stems` is given, one WAV file per channel into that directory.
+/// See `render_daw_to`. Also returns the path and the number of clipped samples of every written file, master first.
+pub fn render_daw_to_files<P>(
+    daw: &mut DAW,
+    sample_rate: f64,
+    path: P,
+    stems: Option<&Path>,
+    processed: bool,
+    options: &WavOptions,
+    control: &RenderControl,
+) -> Result<(RenderReport, Vec<(PathBuf, usize)>), anyhow::Error>
+where
+    P: AsRef<Path>,
+{
+    let mut paths = vec![path.as_ref().to_path_buf()];
+    if let Some(dir) = stems {
+        std::fs::create_dir_all(dir)?;
+        for (i, channel) in daw.channels.iter().enumerate() {
+            paths.push(stem_path(dir, i, &channel.channel.name));
+        }
+    }
+    let mut writers = paths
+        .iter()
+        .map(|path| WavWriter::create(path, options, 2, sample_rate))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (master, stems) = writers.split_at_mut(1);
+    let report = render_daw_to(daw, sample_rate, &mut master[0], stems, processed, control)?;
+
+    let mut clipped = Vec::new();
+    for writer in writers {
+        clipped.push(writer.finish()?.1);
+    }
+    Ok((report, paths.into_iter().zip(clipped).collect()))
 }
 
+/// Mixes the rendered channels through the buses into the master and renders the tail of the master.
+/// The latency of the mix is removed from the start of the master. Also returns the gain reduction of
+/// the master processors.
 fn render_master(
-    master: &mut Channel,
-    channels: &[Vec<(f64, f64)>],
+    daw: &mut DAW,
+    channels: &[Samples],
+    taps: &[Taps],
     sample_count: usize,
     sample_rate: f64,
-) -> Vec<(f64, f64)> {
-    let delta_time = 1.0 / sample_rate;
+    progress: &ProgressTracker,
+) -> Result<(Samples, Vec<f64>), RenderCancelled> {
+    let tail = daw.tail.clone();
+    let length = channels.iter().map(Vec::len).fold(sample_count, max);
+    let max_count = sample_count + (tail.max_length.as_secs_f64() * sample_rate) as usize;
+    let window = (tail.window.as_secs_f64() * sample_rate) as usize;
+    let mut mixer = Mixer::new(daw);
 
-    (0..sample_count)
-        .into_par_iter()
-        .map(|x| {
-            let time = x as f64 * delta_time;
-            (
-                time,
-                channels
-                    .iter()
-                    .map(|vec| vec[x])
-                    .fold((0.0, 0.0), |acc, x| (acc.0 + x.0, acc.1 + x.1)),
-            )
-        })
-        .collect::<Vec<(f64, (f64, f64))>>()
-        .into_iter()
-        .map(|(time, x)| {
-            let input = [x.0, x.1].into();
-            let output = master.tick(time, &input);
-            (output[0], output[1])
-        })
-        .collect()
+    let mut samples = Vec::with_capacity(length);
+    let mut silent = 0;
+    let mut reported = 0;
+    for i in 0..max_count {
+        if i >= length && silent >= window {
+            break;
+        }
+        if i % BLOCK_SIZE == 0 {
+            progress.check()?;
+            progress.add(i - reported);
+            reported = i;
+        }
+        let time = i as f64 / sample_rate;
+        let output = mixer.mix(daw, time, |j| {
+            let output = channels[j].get(i).copied().unwrap_or((0.0, 0.0));
+            let pre_fader = match &taps[j].pre_fader {
+                Some(pre_fader) => pre_fader.get(i).copied().unwrap_or((0.0, 0.0)),
+                None => output,
+            };
+            (output, pre_fader)
+        });
+        let sample = (output[0], output[1]);
+        silent = if i >= sample_count && tail.is_silent(sample) {
+            silent + 1
+        } else {
+            0
+        };
+        samples.push(sample);
+    }
+    progress.add(samples.len() - reported);
+    samples.drain(..min(mixer.latency, samples.len()));
+    Ok((samples, mixer.gain_reduction))
 }
 
 fn render_channel(
     channel: &mut SynthChannel,
     sample_count: usize,
-    sample_rate: f64,
-) -> Vec<(f64, f64)> {
+    tail: &TailSettings,
+    record_dry: bool,
+    key: &[(f64, f64)],
+    index: usize,
+    progress: &ProgressTracker,
+) -> Result<(Samples, Taps), RenderCancelled> {
+    let sample_rate = channel.sample_rate;
+    let max_count = sample_count + (tail.max_length.as_secs_f64() * sample_rate) as usize;
+    let window = (tail.window.as_secs_f64() * sample_rate) as usize;
     let mut samples = Vec::with_capacity(sample_count);
-    let delta_time = 1.0 / sample_rate;
+    let mut taps = Taps::new(channel.has_pre_fader_sends(), record_dry, sample_count);
+    let mut block = vec![[0.0, 0.0].into(); BLOCK_SIZE];
+    let mut silent = 0;
 
-    for i in 0..sample_count {
-        let time = i as f64 * delta_time;
-        let sample = channel.tick(time);
-        samples.push((sample[0], sample[1]))
+    for start in (0..max_count).step_by(BLOCK_SIZE) {
+        if start >= sample_count && silent >= window {
+            break;
+        }
+        progress.check()?;
+        let block = &mut block[..min(BLOCK_SIZE, max_count - start)];
+        let key = key.get(start..).unwrap_or_default();
+        channel.process(start, block, &mut taps, &key[..min(block.len(), key.len())]);
+        for (i, x) in block.iter().enumerate() {
+            let sample = (x[0], x[1]);
+            silent = if start + i >= sample_count && tail.is_silent(sample) {
+                silent + 1
+            } else {
+                0
+            };
+            samples.push(sample);
+        }
+        progress.channel(index, start + block.len(), block.len());
     }
 
-    samples
+    Ok((samples, taps))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limited_master_is_not_clipped() {
+        let mut limiter = TruePeakLimiter::new(-1.0, 0.1);
+        limiter.set_sample_rate(DEFAULT_SR);
+        let master: Vec<(f64, f64)> = (0..DEFAULT_SR as usize)
+            .map(|i| {
+                let x = 3.0 * sin(TAU * 440.0 * i as f64 / DEFAULT_SR);
+                let y = limiter.tick(i as f64 / DEFAULT_SR, &Frame::from([x, -x]));
+                (y[0], y[1])
+            })
+            .collect();
+        let audio = RenderedAudio {
+            master,
+            channels: Vec::new(),
+            dry_channels: Vec::new(),
+            names: Vec::new(),
+            report: RenderReport::default(),
+        };
+        let (wave, report) = audio.master_wave_with_report(DEFAULT_SR, Normalization::Peak);
+        assert!(report.peak <= db_amp(-1.0));
+        assert_eq!(report.clipped, 0);
+        assert_eq!(RenderedAudio::sample_peak(&wave), 1.0);
+    }
 }
@@ -0,0 +1,62 @@
+use std::ops::RangeInclusive;
+
+use crate::prelude::*;
+
+/// The (0-based) MIDI channel that General MIDI reserves for percussion.
+pub const GM_PERCUSSION_CHANNEL: u8 = 9;
+
+/// Maps General MIDI program numbers to instruments, so that channels can be created automatically.
+/// Programs are matched against the ranges in order; unmatched programs use the fallback instrument.
+/// Tracks on the percussion channel always use the percussion instrument.
+#[derive(Clone)]
+pub struct GMMapping {
+    programs: Vec<(RangeInclusive<u8>, Box<dyn MidiInstrument>)>,
+    percussion: Box<dyn MidiInstrument>,
+    fallback: Box<dyn MidiInstrument>,
+}
+
+impl GMMapping {
+    pub fn new(percussion: Box<dyn MidiInstrument>, fallback: Box<dyn MidiInstrument>) -> Self {
+        Self {
+            programs: Vec::new(),
+            percussion,
+            fallback,
+        }
+    }
+    pub fn map(
+        mut self,
+        programs: RangeInclusive<u8>,
+        instrument: Box<dyn MidiInstrument>,
+    ) -> Self {
+        self.programs.push((programs, instrument));
+        self
+    }
+    pub fn instrument(&self, program: u8, channel: u8) -> &dyn MidiInstrument {
+        if channel == GM_PERCUSSION_CHANNEL {
+            return self.percussion.as_ref();
+        }
+        self.programs
+            .iter()
+            .find(|(range, _)| range.contains(&program))
+            .map_or(self.fallback.as_ref(), |(_, instrument)| {
+                instrument.as_ref()
+            })
+    }
+}
+
+impl Default for GMMapping {
+    /// Maps the General MIDI instrument families to the closest built-in instrument.
+    fn default() -> Self {
+        Self::new(gm_percussion(), Box::new(piano()))
+            .map(0..=15, Box::new(piano())) // Piano, Chromatic Percussion
+            .map(16..=23, Box::new(flute())) // Organ
+            .map(24..=31, Box::new(piano())) // Guitar
+            .map(32..=39, Box::new(fm())) // Bass
+            .map(40..=55, Box::new(violin())) // Strings, Ensemble
+            .map(56..=63, Box::new(fm())) // Brass
+            .map(64..=79, Box::new(flute())) // Reed, Pipe
+            .map(80..=87, Box::new(fm())) // Synth Lead
+            .map(88..=95, Box::new(violin())) // Synth Pad
+            .map(96..=127, Box::new(fm())) // Synth Effects, Ethnic, Percussive, Sound Effects
+    }
+}
@@ -1,6 +1,7 @@
 use crate::prelude::*;
 use dyn_clone::{clone_trait_object, DynClone};
 use fundsp::prelude::*;
+use serde::{Deserialize, Deserializer, Serialize};
 
 pub trait MidiInstrument: DynClone + Send + Sync {
     fn build_synth(&self) -> Box<dyn Synthesizer>;
@@ -23,39 +24,72 @@ where
     }
 }
 
-#[derive(Clone)]
+/// Vibrato whose depth follows an envelope. The modulation wheel adds up to `modulation` extra depth.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Vibrato {
     pub strength: f64,
     pub frequency: f64,
     pub envelope: (f64, f64, f64, f64),
+    pub modulation: f64,
+}
+
+impl Default for Vibrato {
+    /// The vibrato of the violin preset.
+    fn default() -> Self {
+        Self::new(0.005, 5.0, (0.4, 0.0, 1.0, 0.5)).with_modulation(0.02)
+    }
 }
 
 impl Vibrato {
-    pub fn new(
-        strength: f64,
-        frequency: f64,
-        freq_envelope: (f64, f64, f64, f64),
-    ) -> Self {
+    pub fn new(strength: f64, frequency: f64, freq_envelope: (f64, f64, f64, f64)) -> Self {
         Self {
             strength,
             frequency,
             envelope: freq_envelope,
+            modulation: 0.0,
         }
     }
-    pub fn build(&self) -> An<impl AudioNode<Sample = f64, Inputs = U2, Outputs = U1>> {
-        let freq_envelope = pass() | make_adsr(self.envelope);
-        let freq_graph = freq_envelope
-            >> pass()
+    pub fn with_modulation(mut self, modulation: f64) -> Self {
+        self.modulation = modulation;
+        self
+    }
+    /// Deserializes a vibrato whose missing fields are taken from `preset`.
+    fn deserialize_over<'de, D: Deserializer<'de>>(
+        deserializer: D,
+        preset: Vibrato,
+    ) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Fields {
+            strength: Option<f64>,
+            frequency: Option<f64>,
+            envelope: Option<(f64, f64, f64, f64)>,
+            modulation: Option<f64>,
+        }
+        let fields = Fields::deserialize(deserializer)?;
+        Ok(Self {
+            strength: fields.strength.unwrap_or(preset.strength),
+            frequency: fields.frequency.unwrap_or(preset.frequency),
+            envelope: fields.envelope.unwrap_or(preset.envelope),
+            modulation: fields.modulation.unwrap_or(preset.modulation),
+        })
+    }
+    /// Takes frequency, adsr control and modulation wheel as inputs and outputs the modulated frequency.
+    pub fn build(&self) -> An<impl AudioNode<Sample = f64, Inputs = U3, Outputs = U1>> {
+        let freq_envelope = pass() | make_adsr(self.envelope) | pass();
+        freq_envelope
+            >> (pass()
                 * (1.0
                     + pass()
-                        * self.strength
-                        * (dc(self.frequency) >> An(Sine::with_phase(DEFAULT_SR, Some(0.0)))));
-        freq_graph
+                        * (self.strength + pass() * self.modulation)
+                        * (dc(self.frequency) >> An(Sine::with_phase(DEFAULT_SR, Some(0.0))))))
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Violin {
+    #[serde(deserialize_with = "violin_vibrato")]
     vibrato: Vibrato,
     envelope: (f64, f64, f64, f64),
 }
@@ -66,13 +100,23 @@ impl Violin {
     }
 }
 
+impl Default for Violin {
+    fn default() -> Self {
+        violin()
+    }
+}
+
+fn violin_vibrato<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vibrato, D::Error> {
+    Vibrato::deserialize_over(deserializer, violin().vibrato)
+}
+
 impl MidiInstrument for Violin {
     fn build_synth(&self) -> Box<dyn Synthesizer> {
-        let signal = square() * 0.7 & saw() * 0.3;
+        let signal = (square() * 0.7) & (saw() * 0.3);
 
-        let freq_graph = select([0, 2]) >> self.vibrato.build() >> signal;
-        let graph = freq_graph ^ (sink() | pass() * make_adsr(self.envelope));
-        let unit = graph >> pass() * pass() >> pan(0.0);
+        let freq_graph = select([0, 2, 3]) >> self.vibrato.build() >> signal;
+        let graph = freq_graph ^ (sink() | (pass() * make_adsr(self.envelope)) | sink());
+        let unit = graph >> (pass() * pass()) >> pan(0.0);
 
         SimpleSynth::boxed(8, Box::new(unit))
     }
@@ -82,7 +126,8 @@ impl MidiInstrument for Violin {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Piano {
     envelope: (f64, f64, f64, f64),
 }
@@ -93,11 +138,17 @@ impl Piano {
     }
 }
 
+impl Default for Piano {
+    fn default() -> Self {
+        piano()
+    }
+}
+
 impl MidiInstrument for Piano {
     fn build_synth(&self) -> Box<dyn Synthesizer> {
         let signal = soft_saw();
 
-        let graph = signal * pass() * make_adsr(self.envelope);
+        let graph = (signal * pass() * make_adsr(self.envelope)) | sink();
         let unit = graph >> pan(0.0);
 
         SimpleSynth::boxed(8, Box::new(unit))
@@ -108,8 +159,10 @@ impl MidiInstrument for Piano {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Flute {
+    #[serde(deserialize_with = "flute_vibrato")]
     vibrato: Vibrato,
     envelope: (f64, f64, f64, f64),
 }
@@ -120,13 +173,23 @@ impl Flute {
     }
 }
 
+impl Default for Flute {
+    fn default() -> Self {
+        flute()
+    }
+}
+
+fn flute_vibrato<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vibrato, D::Error> {
+    Vibrato::deserialize_over(deserializer, flute().vibrato)
+}
+
 impl MidiInstrument for Flute {
     fn build_synth(&self) -> Box<dyn Synthesizer> {
-        let signal = triangle() * 0.8 & sine() * 0.2;
+        let signal = (triangle() * 0.8) & (sine() * 0.2);
 
-        let freq_graph = select([0, 2]) >> self.vibrato.build() >> signal;
-        let graph = freq_graph ^ (sink() | pass() * make_adsr(self.envelope));
-        let unit = graph >> pass() * pass() >> pan(0.0);
+        let freq_graph = select([0, 2, 3]) >> self.vibrato.build() >> signal;
+        let graph = freq_graph ^ (sink() | (pass() * make_adsr(self.envelope)) | sink());
+        let unit = graph >> (pass() * pass()) >> pan(0.0);
 
         SimpleSynth::boxed(8, Box::new(unit))
     }
@@ -136,8 +199,10 @@ impl MidiInstrument for Flute {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct FM {
+    #[serde(deserialize_with = "fm_vibrato")]
     vibrato: Vibrato,
     envelope: (f64, f64, f64, f64),
     fm: (f64, f64),
@@ -153,13 +218,23 @@ impl FM {
     }
 }
 
+impl Default for FM {
+    fn default() -> Self {
+        fm()
+    }
+}
+
+fn fm_vibrato<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vibrato, D::Error> {
+    Vibrato::deserialize_over(deserializer, fm().vibrato)
+}
+
 impl MidiInstrument for FM {
     fn build_synth(&self) -> Box<dyn Synthesizer> {
         let signal = An(FreqMod::new(self.fm.0, self.fm.1));
 
-        let freq_graph = select([0, 2]) >> self.vibrato.build() >> signal;
-        let graph = freq_graph ^ (sink() | pass() * make_adsr(self.envelope));
-        let unit = graph >> pass() * pass() >> pan(0.0);
+        let freq_graph = select([0, 2, 3]) >> self.vibrato.build() >> signal;
+        let graph = freq_graph ^ (sink() | (pass() * make_adsr(self.envelope)) | sink());
+        let unit = graph >> (pass() * pass()) >> pan(0.0);
 
         SimpleSynth::boxed(8, Box::new(unit))
     }
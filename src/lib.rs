@@ -3,15 +3,21 @@ use std::marker::PhantomData;
 
 use fundsp::prelude::*;
 
+pub mod automation;
 pub mod daw;
+pub mod general_midi;
 pub mod instrument;
+pub mod loudness;
 pub mod midi;
 pub mod percussion;
 pub mod playback;
 pub mod prelude;
 pub mod processor;
+pub mod project;
+pub mod report;
 pub mod score;
 pub mod synthesizer;
+pub mod wav;
 
 /// A better ADSR envelope implementation that doesn't use shared variables.
 /// It prevents abrupt changes in the output when the control signal changes.
@@ -0,0 +1,299 @@
+use std::f64::consts::PI;
+
+use fundsp::prelude::amp_db;
+use fundsp::wave::Wave64;
+
+/// Loudness and true-peak measurements of a stereo signal according to ITU-R BS.1770-4.
+/// Loudness values are in LUFS, peaks are linear. Silence has a loudness of negative infinity.
+#[derive(Clone, Debug)]
+pub struct Loudness {
+    pub integrated: f64,
+    /// Loudness over 400 ms windows, every 100 ms.
+    pub momentary: Vec<f64>,
+    /// Loudness over 3 s windows, every 100 ms.
+    pub short_term: Vec<f64>,
+    pub true_peak: f64,
+}
+
+impl Loudness {
+    pub fn measure(wave: &Wave64) -> Self {
+        let mut meter = LoudnessMeter::new(wave.sample_rate());
+        for i in 0..wave.len() {
+            let right = if wave.channels() > 1 { 1 } else { 0 };
+            meter.push(wave.at(0, i), wave.at(right, i));
+        }
+        meter.loudness()
+    }
+    pub fn momentary_max(&self) -> f64 {
+        self.momentary
+            .iter()
+            .copied()
+            .fold(f64::NEG_INFINITY, f64::max)
+    }
+    pub fn short_term_max(&self) -> f64 {
+        self.short_term
+            .iter()
+            .copied()
+            .fold(f64::NEG_INFINITY, f64::max)
+    }
+    pub fn true_peak_db(&self) -> f64 {
+        amp_db(self.true_peak)
+    }
+}
+
+/// Measures loudness incrementally, so that it can be used while rendering.
+#[derive(Clone)]
+pub struct LoudnessMeter {
+    filters: [(Biquad, Biquad); 2],
+    block_size: usize,
+    block_energy: f64,
+    block_samples: usize,
+    /// Mean square energy of every 100 ms block.
+    blocks: Vec<f64>,
+    true_peak: [TruePeak; 2],
+}
+
+impl LoudnessMeter {
+    pub fn new(sample_rate: f64) -> Self {
+        let filters = (
+            Biquad::k_weighting_shelf(sample_rate),
+            Biquad::k_weighting_highpass(sample_rate),
+        );
+        Self {
+            filters: [filters.clone(), filters],
+            block_size: (sample_rate * 0.1).round() as usize,
+            block_energy: 0.0,
+            block_samples: 0,
+            blocks: Vec::new(),
+            true_peak: [TruePeak::new(), TruePeak::new()],
+        }
+    }
+    pub fn push(&mut self, left: f64, right: f64) {
+        for (i, x) in [left, right].into_iter().enumerate() {
+            let (shelf, highpass) = &mut self.filters[i];
+            let weighted = highpass.tick(shelf.tick(x));
+            self.block_energy += weighted * weighted;
+            self.true_peak[i].push(x);
+        }
+        self.block_samples += 1;
+        if self.block_samples == self.block_size {
+            self.blocks.push(self.block_energy / self.block_size as f64);
+            self.block_energy = 0.0;
+            self.block_samples = 0;
+        }
+    }
+    pub fn true_peak(&self) -> f64 {
+        self.true_peak[0].peak.max(self.true_peak[1].peak)
+    }
+    pub fn loudness(&self) -> Loudness {
+        let momentary = Self::windows(&self.blocks, 4);
+        let short_term = Self::windows(&self.blocks, 30)
+            .into_iter()
+            .map(energy_lufs)
+            .collect();
+
+        let absolute: Vec<f64> = momentary
+            .iter()
+            .copied()
+            .filter(|x| energy_lufs(*x) > -70.0)
+            .collect();
+        let relative_gate = energy_lufs(mean(&absolute)) - 10.0;
+        let relative: Vec<f64> = absolute
+            .into_iter()
+            .filter(|x| energy_lufs(*x) > relative_gate)
+            .collect();
+
+        Loudness {
+            integrated: energy_lufs(mean(&relative)),
+            momentary: momentary.into_iter().map(energy_lufs).collect(),
+            short_term,
+            true_peak: self.true_peak(),
+        }
+    }
+    /// Returns the mean energy of every run of `length` consecutive blocks.
+    fn windows(blocks: &[f64], length: usize) -> Vec<f64> {
+        blocks.windows(length).map(mean).collect()
+    }
+}
+
+fn mean(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        0.0
+    } else {
+        values.iter().sum::<f64>() / values.len() as f64
+    }
+}
+
+fn energy_lufs(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+#[derive(Clone)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self {
+            b,
+            a,
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+    /// The high shelf stage of the K-weighting filter, which models the acoustic effect of the head.
+    fn k_weighting_shelf(sample_rate: f64) -> Self {
+        let f0 = 1681.974450955533;
+        let gain = 3.999843853973347;
+        let q = 0.7071752369554196;
+        let k = (PI * f0 / sample_rate).tan();
+        let vh = 10.0_f64.powf(gain / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        Self::new(
+            [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        )
+    }
+    /// The highpass stage of the K-weighting filter (RLB weighting).
+    fn k_weighting_highpass(sample_rate: f64) -> Self {
+        let f0 = 38.13547087602444;
+        let q = 0.5003270373238773;
+        let k = (PI * f0 / sample_rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        Self::new(
+            [1.0, -2.0, 1.0],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        )
+    }
+    fn tick(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+const OVERSAMPLING: usize = 4;
+const TAPS_PER_PHASE: usize = 12;
+
+/// Estimates the true peak by upsampling 4x with a windowed sinc interpolator.
+#[derive(Clone)]
+pub(crate) struct TruePeak {
+    coefficients: [[f64; TAPS_PER_PHASE]; OVERSAMPLING],
+    history: [f64; TAPS_PER_PHASE],
+    position: usize,
+    pub peak: f64,
+}
+
+impl TruePeak {
+    /// The interpolated peaks around a sample are returned up to this many pushes after it.
+    pub const LATENCY: usize = TAPS_PER_PHASE / 2 + 1;
+
+    pub fn new() -> Self {
+        let mut coefficients = [[0.0; TAPS_PER_PHASE]; OVERSAMPLING];
+        for (phase, taps) in coefficients.iter_mut().enumerate() {
+            for (tap, coefficient) in taps.iter_mut().enumerate() {
+                *coefficient = Self::coefficient(phase, tap);
+            }
+        }
+        Self {
+            coefficients,
+            history: [0.0; TAPS_PER_PHASE],
+            position: 0,
+            peak: 0.0,
+        }
+    }
+    fn coefficient(phase: usize, tap: usize) -> f64 {
+        let length = (OVERSAMPLING * TAPS_PER_PHASE) as f64;
+        let n = (tap * OVERSAMPLING + phase) as f64;
+        let t = (n - (length - 1.0) / 2.0) / OVERSAMPLING as f64;
+        let sinc = if t == 0.0 {
+            1.0
+        } else {
+            (PI * t).sin() / (PI * t)
+        };
+        let window = 0.5 - 0.5 * (2.0 * PI * (n + 0.5) / length).cos();
+        sinc * window
+    }
+    /// Pushes a sample and returns the largest absolute value of the interpolated samples.
+    pub fn push(&mut self, x: f64) -> f64 {
+        self.history[self.position] = x;
+        self.position = (self.position + 1) % TAPS_PER_PHASE;
+        let mut peak = x.abs();
+        for taps in self.coefficients.iter() {
+            let value: f64 = taps
+                .iter()
+                .enumerate()
+                .map(|(tap, coefficient)| {
+                    let index = (self.position + TAPS_PER_PHASE - 1 - tap) % TAPS_PER_PHASE;
+                    self.history[index] * coefficient
+                })
+                .sum();
+            peak = peak.max(value.abs());
+        }
+        self.peak = self.peak.max(peak);
+        peak
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A stereo sine with the same signal in both channels.
+    fn sine(frequency: f64, amplitude: f64, phase: f64, seconds: f64, sample_rate: f64) -> Wave64 {
+        let samples: Vec<f64> = (0..(seconds * sample_rate) as usize)
+            .map(|i| amplitude * (2.0 * PI * frequency * i as f64 / sample_rate + phase).sin())
+            .collect();
+        let mut wave = Wave64::new(0, sample_rate);
+        wave.push_channel(&samples);
+        wave.push_channel(&samples);
+        wave
+    }
+
+    #[test]
+    fn sine_at_minus_20_dbfs_is_minus_20_lufs() {
+        for sample_rate in [44100.0, 48000.0] {
+            let loudness = Loudness::measure(&sine(1000.0, 0.1, 0.0, 5.0, sample_rate));
+            assert!(
+                (loudness.integrated + 20.0).abs() < 0.05,
+                "{} LUFS at {} Hz",
+                loudness.integrated,
+                sample_rate
+            );
+            assert!((loudness.momentary_max() + 20.0).abs() < 0.05);
+            assert!((loudness.short_term_max() + 20.0).abs() < 0.05);
+        }
+    }
+
+    #[test]
+    fn silence_has_no_loudness() {
+        let loudness = Loudness::measure(&sine(1000.0, 0.0, 0.0, 1.0, 48000.0));
+        assert_eq!(loudness.integrated, f64::NEG_INFINITY);
+        assert_eq!(loudness.true_peak, 0.0);
+    }
+
+    #[test]
+    fn true_peak_between_samples() {
+        // At a quarter of the sample rate and a phase of 45 degrees, every sample is at 0.707
+        // while the peaks lie between the samples.
+        let loudness = Loudness::measure(&sine(12000.0, 1.0, PI / 4.0, 1.0, 48000.0));
+        assert!(
+            (loudness.true_peak - 1.0).abs() < 0.05,
+            "{}",
+            loudness.true_peak
+        );
+        assert!(loudness.true_peak_db().abs() < 0.5);
+    }
+}
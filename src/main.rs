@@ -1,33 +1,296 @@
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use clap::{Parser, ValueEnum};
+use fundsp::prelude::amp_db;
 use soundmaker::prelude::*;
 
-fn main() {
-    let midi = std::fs::read("temp/Chill Beats.mid").unwrap();
-    let sample_rate = find_sample_rate();
-    let mut daw = DAW::new();
+/// Renders a MIDI file to a WAV file using the built-in instruments.
+#[derive(Parser)]
+#[command(version, about)]
+struct Args {
+    /// The MIDI file to render
+    #[arg(short, long, required_unless_present = "project")]
+    input: Option<PathBuf>,
+    /// A project file (TOML or JSON) describing the MIDI file, instruments and effects
+    #[arg(long, conflicts_with_all = ["input", "split_channels", "instruments"])]
+    project: Option<PathBuf>,
+    /// The WAV file to write
+    #[arg(short, long)]
+    output: PathBuf,
+    /// The sample rate of the output in Hz
+    #[arg(short, long, default_value_t = 44100.0)]
+    sample_rate: f64,
+    /// Don't normalize the output to full scale
+    #[arg(long)]
+    no_normalize: bool,
+    /// Normalize the output to this integrated loudness in LUFS instead of to full scale
+    #[arg(
+        long,
+        value_name = "LUFS",
+        allow_negative_numbers = true,
+        conflicts_with = "no_normalize"
+    )]
+    loudness: Option<f64>,
+    /// The true-peak ceiling in dBTP used with --loudness
+    #[arg(long, value_name = "DBTP", default_value_t = -1.0, allow_negative_numbers = true, requires = "loudness")]
+    true_peak: f64,
+    /// The bit depth of the output
+    #[arg(short, long, value_enum, default_value_t = BitDepth::Float32)]
+    bits: BitDepth,
+    /// The dither used for integer output
+    #[arg(short, long, value_enum, default_value_t = DitherKind::Tpdf)]
+    dither: DitherKind,
+    /// Also write one WAV file per channel into this directory.
+    /// Unless normalization is disabled, all stems share one gain so that they stay balanced.
+    #[arg(long, value_name = "DIR")]
+    stems: Option<PathBuf>,
+    /// Write the stems without the processors of their channels
+    #[arg(long, requires = "stems")]
+    dry_stems: bool,
+    /// Write the output while rendering instead of keeping the whole song in memory.
+    /// The output is not normalized.
+    #[arg(long, conflicts_with_all = ["loudness", "play"])]
+    stream: bool,
+    /// Mutes a channel (0-based). Muted channels are not rendered.
+    #[arg(long, value_name = "CHANNEL")]
+    mute: Vec<usize>,
+    /// Solos a channel (0-based), so that only soloed channels are rendered
+    #[arg(long, value_name = "CHANNEL")]
+    solo: Vec<usize>,
+    /// Don't print the track assignment and render summary
+    #[arg(short, long)]
+    quiet: bool,
+    /// Play the song after rendering it
+    #[arg(short, long)]
+    play: bool,
+    /// Split the tracks by MIDI channel before assigning instruments
+    #[arg(long)]
+    split_channels: bool,
+    /// Sets the instrument of a track (0-based), e.g. "2=violin".
+    /// Tracks without an instrument are assigned one based on their General MIDI program.
+    /// Available instruments: violin, flute, piano, fm, percussion, none.
+    #[arg(long = "instrument", value_name = "TRACK=INSTRUMENT", value_parser = parse_instrument)]
+    instruments: Vec<(usize, InstrumentKind)>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum BitDepth {
+    /// 16-bit integer
+    #[value(name = "16")]
+    Int16,
+    /// 24-bit integer
+    #[value(name = "24")]
+    Int24,
+    /// 32-bit float
+    #[value(name = "32")]
+    Float32,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum DitherKind {
+    /// No dither, the samples are rounded
+    None,
+    /// Triangular (TPDF) dither
+    Tpdf,
+    /// TPDF dither with noise shaping
+    Shaped,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum InstrumentKind {
+    Violin,
+    Flute,
+    Piano,
+    Fm,
+    Percussion,
+    None,
+}
 
-    let violin = violin();
-    let flute = flute();
+impl InstrumentKind {
+    fn build(self) -> Box<dyn MidiInstrument> {
+        match self {
+            Self::Violin => Box::new(violin()),
+            Self::Flute => Box::new(flute()),
+            Self::Piano => Box::new(piano()),
+            Self::Fm => Box::new(fm()),
+            Self::Percussion => gm_percussion(),
+            Self::None => Box::new(SinkSynth),
+        }
+    }
+}
 
-    let percussion = percussion(vec![
-        Percussion::BassDrum(36, 0.4),
-        Percussion::SnareDrum(38, 0.7),
-        Percussion::HiHat(44, 1.0),
-        Percussion::Shaker(70, 1.0),
-    ]);
+impl Args {
+    fn wav_options(&self) -> WavOptions {
+        let format = match self.bits {
+            BitDepth::Int16 => WavFormat::Int16,
+            BitDepth::Int24 => WavFormat::Int24,
+            BitDepth::Float32 => WavFormat::Float32,
+        };
+        let dither = match self.dither {
+            DitherKind::None => Dither::None,
+            DitherKind::Tpdf => Dither::Tpdf,
+            DitherKind::Shaped => Dither::TpdfShaped,
+        };
+        WavOptions::new(format, dither)
+    }
+    fn normalization(&self) -> Normalization {
+        match self.loudness {
+            _ if self.no_normalize => Normalization::None,
+            Some(target) => Normalization::Loudness {
+                target,
+                true_peak_ceiling: self.true_peak,
+            },
+            None => Normalization::Peak,
+        }
+    }
+}
 
-    daw.add_instrument("Flute".to_string(), &flute, 1.0, 0.0);
-    daw.add_instrument("Percussion 1".to_string(), percussion.as_ref(), 1.0, 0.0);
-    daw.add_instrument("Percussion 2".to_string(), percussion.as_ref(), 1.0, 0.0);
-    daw.add_instrument("Viola".to_string(), &violin, 1.0, 0.0);
-    daw.add_instrument("Cello".to_string(), &violin, 1.0, 0.0);
+fn parse_instrument(arg: &str) -> Result<(usize, InstrumentKind), String> {
+    let (track, name) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected TRACK=INSTRUMENT, got \"{arg}\""))?;
+    let track = track
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid track index \"{track}\""))?;
+    let kind = InstrumentKind::from_str(name.trim(), true)?;
+    Ok((track, kind))
+}
 
-    daw.set_midi_bytes(&midi);
+fn main() -> Result<(), anyhow::Error> {
+    let args = Args::parse();
 
-    let render = render_daw(&mut daw, sample_rate);
+    let (mut daw, midi_report) = match (&args.project, &args.input) {
+        (Some(project), _) => Project::load(project)?.build_with_report()?,
+        (None, Some(input)) => build_daw(input, &args)?,
+        (None, None) => unreachable!("clap requires either a project or an input"),
+    };
+    for (channels, solo) in [(&args.mute, false), (&args.solo, true)] {
+        for &channel in channels {
+            if channel >= daw.channel_count {
+                eprintln!(
+                    "Ignoring channel {}, the DAW only has {} channels.",
+                    channel, daw.channel_count
+                );
+            } else if solo {
+                daw[channel].solo = true;
+            } else {
+                daw[channel].mute = true;
+            }
+        }
+    }
+    if !args.quiet {
+        daw.logger = Some(Arc::new(StderrLogger));
+        StderrLogger.log(&midi_report.summary());
+    }
+
+    let options = args.wav_options();
+    if args.stream {
+        let (_, files) = render_daw_to_files(
+            &mut daw,
+            args.sample_rate,
+            &args.output,
+            args.stems.as_deref(),
+            !args.dry_stems,
+            &options,
+            &progress_bar(),
+        )?;
+        for (path, clipped) in files {
+            report_clipping(&path, clipped, &options);
+        }
+        return Ok(());
+    }
+
+    daw.record_dry = args.dry_stems;
+    let render = render_daw_with(&mut daw, args.sample_rate, &progress_bar())?;
+    if let Some(dir) = &args.stems {
+        let normalization = if args.no_normalize {
+            StemNormalization::None
+        } else {
+            StemNormalization::Shared
+        };
+        let stems = render.save_stems(
+            dir,
+            args.sample_rate,
+            !args.dry_stems,
+            normalization,
+            &options,
+        )?;
+        for (path, clipped) in stems {
+            report_clipping(&path, clipped, &options);
+        }
+    }
+    let (wave, normalization) =
+        render.master_wave_with_report(args.sample_rate, args.normalization());
+    if !args.quiet && normalization.gain != 1.0 {
+        eprintln!(
+            "Normalized with a gain of {:.1} dB.",
+            amp_db(normalization.gain)
+        );
+    }
+    let clipped = save_wav(&wave, &args.output, &options)?;
+    report_clipping(&args.output, clipped, &options);
+
+    if args.play {
+        play_wave(wave)?;
+    }
+
+    Ok(())
+}
+
+/// Shows the overall progress on stderr if it is a terminal.
+fn progress_bar() -> RenderControl {
+    if !std::io::stderr().is_terminal() {
+        return RenderControl::new();
+    }
+    let percent = AtomicUsize::new(usize::MAX);
+    RenderControl::new().with_progress(move |progress| {
+        let current = (progress.fraction() * 100.0) as usize;
+        if progress.channel.is_none() && percent.swap(current, Ordering::Relaxed) != current {
+            eprint!("\rRendering: {:3}%", current);
+            if current == 100 {
+                eprintln!();
+            }
+        }
+    })
+}
+
+/// Float output keeps samples beyond full scale, so they are only reported as such.
+fn report_clipping(path: &Path, clipped: usize, options: &WavOptions) {
+    if clipped == 0 {
+        return;
+    }
+    if options.format == WavFormat::Float32 {
+        eprintln!(
+            "{} samples exceeded full scale in {}.",
+            clipped,
+            path.display()
+        );
+    } else {
+        eprintln!("{} samples were clipped in {}.", clipped, path.display());
+    }
+}
+
+fn build_daw(input: &Path, args: &Args) -> Result<(DAW, MidiReport), anyhow::Error> {
+    let bytes = std::fs::read(input)?;
+    let mut daw = DAW::new();
+    daw.gm_mapping = Some(GMMapping::default());
+    daw.split_channels = args.split_channels;
+    let report = daw.set_midi_bytes(&bytes)?;
 
-    render.save("output/chill_beats.bin").unwrap();
-    let wave = render.master_wave(sample_rate, true);
-    wave.save_wav32("output/chill_beats.wav").unwrap();
+    for (track, kind) in args.instruments.iter() {
+        match report.tracks.get(*track).and_then(|x| x.channel) {
+            Some(channel) => daw.set_instrument(channel, kind.build().as_ref())?,
+            None => eprintln!(
+                "Ignoring instrument for track {}, the MIDI only has {} tracks.",
+                track,
+                report.tracks.len()
+            ),
+        }
+    }
 
-    play_wave(wave).unwrap();
+    Ok((daw, report))
 }
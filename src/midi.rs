@@ -1,70 +1,184 @@
+use std::cmp::max;
 use std::time::Duration;
 
+use fundsp::DEFAULT_SR;
 use midly::MetaMessage;
 use midly::MidiMessage;
 use midly::Smf;
+use midly::Timing;
 use midly::Track;
 use midly::TrackEvent;
 use midly::TrackEventKind;
 
+pub const CC_MODULATION: u8 = 1;
+pub const CC_VOLUME: u8 = 7;
+pub const CC_PAN: u8 = 10;
+pub const CC_EXPRESSION: u8 = 11;
+pub const CC_SUSTAIN: u8 = 64;
+pub const CC_SOSTENUTO: u8 = 66;
+
+/// Messages are scheduled on the first sample that is no more than this many seconds before the message.
+/// This keeps rounding errors from delaying messages that fall exactly onto a sample by one sample.
+const TIME_EPSILON: f64 = 1e-9;
+
+/// The reasons why a MIDI file can't be rendered.
+#[derive(Debug)]
+pub enum MidiError {
+    /// The data is not a valid Standard MIDI File.
+    Parse(midly::Error),
+    /// The file doesn't contain any notes.
+    Empty,
+    /// The timing can't be converted to seconds, e.g. zero ticks per beat.
+    UnsupportedTiming(Timing),
+    /// A track is longer than `u32::MAX` ticks.
+    TooLong,
+}
+
+impl std::fmt::Display for MidiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Parse(_) => write!(f, "The data is not a valid MIDI file."),
+            Self::Empty => write!(f, "The MIDI file doesn't contain any notes."),
+            Self::UnsupportedTiming(timing) => {
+                write!(f, "Unsupported MIDI timing: {:?}", timing)
+            }
+            Self::TooLong => write!(f, "A MIDI track is too long."),
+        }
+    }
+}
+
+impl std::error::Error for MidiError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Parse(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<midly::Error> for MidiError {
+    fn from(err: midly::Error) -> Self {
+        Self::Parse(err)
+    }
+}
+
+/// Schedules MIDI messages on sample offsets, so that synthesizers can consume them without polling.
+/// The offsets are computed up front and recomputed whenever the sample rate changes.
 #[derive(Clone)]
 pub struct MidiWrapper {
     midi: Vec<MidiMsg>,
+    samples: Vec<usize>,
     msg_index: usize,
-    current_tempo: (u32, f64, f64),
 }
 
 impl MidiWrapper {
     pub fn new(midi: Vec<MidiMsg>) -> Self {
-        Self {
+        let mut wrapper = Self {
             midi,
+            samples: Vec::new(),
             msg_index: 0,
-            current_tempo: (0, 0.0, 480.0),
-        }
-    }
-    fn consume_message(
-        &mut self,
-        msg: MidiMsg,
-        ticks: u32,
-        time: f64,
-        dropped_notes: &mut Vec<u8>,
-        new_notes: &mut Vec<(u8, f64)>,
-    ) {
-        // println!("consume: {msg:?}");
-        match msg.kind {
-            MsgType::NoteOn(pitch, vel) => {
-                new_notes.push((pitch, vel as f64 / 127.0));
-            }
-            MsgType::NoteOff(pitch) => {
-                dropped_notes.push(pitch);
-            }
-            MsgType::Tempo(tempo) => {
-                self.current_tempo = (ticks, time, tempo);
-            }
+        };
+        wrapper.set_sample_rate(DEFAULT_SR);
+        wrapper
+    }
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.samples = self
+            .midi
+            .iter()
+            .map(|msg| ((msg.time - TIME_EPSILON) * sample_rate).ceil().max(0.0) as usize)
+            .collect();
+    }
+    /// Returns the next message if it is due at or before the given sample.
+    pub fn pop(&mut self, sample: usize) -> Option<MsgType> {
+        let msg = self.midi.get(self.msg_index)?;
+        if self.samples[self.msg_index] <= sample {
+            self.msg_index += 1;
+            Some(msg.kind)
+        } else {
+            None
         }
     }
-    pub fn tick(&mut self, time: f64) -> (Vec<u8>, Vec<(u8, f64)>) {
-        let time_in_tempo = time - self.current_tempo.1;
-        let ticks_in_tempo = (time_in_tempo * self.current_tempo.2) as u32;
-        let ticks = self.current_tempo.0 + ticks_in_tempo;
+    /// Returns the sample on which the next message is due, if there is one.
+    pub fn next_sample(&self) -> Option<usize> {
+        self.samples.get(self.msg_index).copied()
+    }
+    pub fn reset(&mut self) {
+        self.msg_index = 0;
+    }
+}
 
-        let mut dropped_notes = Vec::new();
-        let mut new_notes = Vec::new();
+/// Converts absolute ticks to seconds, taking the timing of the file and all tempo changes into account.
+/// For metrical timing, the tempo is 120 BPM until the first tempo change. Timecode timing ignores tempo changes.
+#[derive(Clone, Debug)]
+pub struct TempoMap {
+    ticks_per_beat: f64,
+    /// (absolute ticks, seconds, microseconds per beat)
+    segments: Vec<(u32, f64, f64)>,
+}
 
-        while let Some(msg) = self.midi.get(self.msg_index) {
-            if ticks >= msg.abs_ticks {
-                self.consume_message(*msg, ticks, time, &mut dropped_notes, &mut new_notes);
-                self.msg_index += 1;
-            } else {
-                break;
+impl TempoMap {
+    pub fn new(timing: Timing, tracks: &[Track]) -> Result<Self, MidiError> {
+        match timing {
+            Timing::Metrical(ticks_per_beat) if ticks_per_beat == 0 => {
+                Err(MidiError::UnsupportedTiming(timing))
             }
+            Timing::Timecode(_, 0) => Err(MidiError::UnsupportedTiming(timing)),
+            Timing::Metrical(ticks_per_beat) => {
+                let mut tempos: Vec<(u32, u32)> = Vec::new();
+                for track in tracks {
+                    let mut abs_ticks = 0;
+                    for msg in track {
+                        abs_ticks = MidiMsg::add_delta(abs_ticks, msg)?;
+                        if let TrackEventKind::Meta(MetaMessage::Tempo(tempo)) = msg.kind {
+                            tempos.push((abs_ticks, tempo.as_int()));
+                        }
+                    }
+                }
+                tempos.sort_by_key(|x| x.0);
+                Ok(Self::from_tempos(ticks_per_beat.as_int() as f64, &tempos))
+            }
+            // SMPTE timing has no beats, so ticks are mapped to beats at 120 BPM.
+            Timing::Timecode(fps, subframes) => Ok(Self::from_tempos(
+                fps.as_f32() as f64 * subframes as f64 / 2.0,
+                &[],
+            )),
         }
+    }
 
-        (dropped_notes, new_notes)
+    /// Creates a tempo map from (absolute ticks, microseconds per beat) pairs, sorted by ticks.
+    pub fn from_tempos(ticks_per_beat: f64, tempos: &[(u32, u32)]) -> Self {
+        let mut segments = vec![(0, 0.0, 500_000.0)];
+        for &(abs_ticks, tempo) in tempos {
+            let seconds = Self::seconds_in(ticks_per_beat, segments[segments.len() - 1], abs_ticks);
+            segments.push((abs_ticks, seconds, tempo as f64));
+        }
+        Self {
+            ticks_per_beat,
+            segments,
+        }
     }
-    pub fn reset(&mut self) {
-        self.msg_index = 0;
-        self.current_tempo = (0, 0.0, 480.0);
+
+    fn seconds_in(ticks_per_beat: f64, segment: (u32, f64, f64), abs_ticks: u32) -> f64 {
+        let ticks = abs_ticks as f64 - segment.0 as f64;
+        segment.1 + ticks * segment.2 / (1_000_000.0 * ticks_per_beat)
+    }
+
+    pub fn seconds(&self, abs_ticks: u32) -> f64 {
+        let index = self.segments.partition_point(|x| x.0 <= abs_ticks);
+        Self::seconds_in(self.ticks_per_beat, self.segments[index - 1], abs_ticks)
+    }
+
+    /// Returns the length of a beat (a quarter note) in seconds at a time in seconds.
+    pub fn beat_duration(&self, seconds: f64) -> f64 {
+        let index = self.segments.partition_point(|x| x.1 <= seconds);
+        self.segments[max(index, 1) - 1].2 / 1_000_000.0
+    }
+}
+
+impl Default for TempoMap {
+    /// 480 ticks per beat at 120 BPM.
+    fn default() -> Self {
+        Self::from_tempos(480.0, &[])
     }
 }
 
@@ -72,112 +186,222 @@ impl MidiWrapper {
 pub struct MidiMsg {
     kind: MsgType,
     abs_ticks: u32,
+    time: f64,
 }
 
 impl MidiMsg {
-    pub fn new(kind: MsgType, abs_ticks: u32) -> Self {
-        Self { kind, abs_ticks }
+    pub fn new(kind: MsgType, abs_ticks: u32, time: f64) -> Self {
+        Self {
+            kind,
+            abs_ticks,
+            time,
+        }
+    }
+    pub fn kind(&self) -> MsgType {
+        self.kind
+    }
+    pub fn time(&self) -> f64 {
+        self.time
+    }
+
+    /// Adds the delta time of a message to the absolute ticks before it.
+    fn add_delta(abs_ticks: u32, msg: &TrackEvent) -> Result<u32, MidiError> {
+        abs_ticks
+            .checked_add(msg.delta.as_int())
+            .ok_or(MidiError::TooLong)
     }
 
-    pub fn convert_track(track: &Track) -> Vec<Self> {
+    pub fn convert_track(track: &Track, tempo_map: &TempoMap) -> Result<Vec<Self>, MidiError> {
         let mut vec = Vec::new();
         let mut abs_ticks = 0;
         for msg in track {
-            let delta_ticks = msg.delta.as_int();
-            abs_ticks += delta_ticks;
+            abs_ticks = Self::add_delta(abs_ticks, msg)?;
+            let new = |kind| Self::new(kind, abs_ticks, tempo_map.seconds(abs_ticks));
             match msg.kind {
                 TrackEventKind::Midi {
                     channel: _,
                     message,
                 } => match message {
                     MidiMessage::NoteOn { key, vel } if vel == 0 => {
-                        vec.push(Self::new(MsgType::NoteOff(key.as_int()), abs_ticks))
+                        vec.push(new(MsgType::NoteOff(key.as_int())))
+                    }
+                    MidiMessage::NoteOn { key, vel } => {
+                        vec.push(new(MsgType::NoteOn(key.as_int(), vel.as_int())))
                     }
-                    MidiMessage::NoteOn { key, vel } => vec.push(Self::new(
-                        MsgType::NoteOn(key.as_int(), vel.as_int()),
-                        abs_ticks,
-                    )),
                     MidiMessage::NoteOff { key, vel: _ } => {
-                        vec.push(Self::new(MsgType::NoteOff(key.as_int()), abs_ticks))
+                        vec.push(new(MsgType::NoteOff(key.as_int())))
+                    }
+                    MidiMessage::PitchBend { bend } => {
+                        vec.push(new(MsgType::PitchBend(bend.as_f64())))
+                    }
+                    MidiMessage::Controller { controller, value } => {
+                        vec.push(new(MsgType::Control(controller.as_int(), value.as_int())))
+                    }
+                    MidiMessage::ProgramChange { program } => {
+                        vec.push(new(MsgType::ProgramChange(program.as_int())))
                     }
                     _ => (),
                 },
-                TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => vec.push(Self::new(
-                    MsgType::Tempo(480_000_000.0 / tempo.as_int() as f64),
-                    abs_ticks,
-                )),
+                TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => {
+                    vec.push(new(MsgType::Tempo(tempo.as_int())))
+                }
                 _ => (),
             }
         }
-        vec
+        Ok(vec)
     }
 
-    pub fn convert_smf(midi: Smf) -> (Vec<Vec<Self>>, Duration) {
-        let mut messages: Vec<Vec<Self>> = midi.tracks.iter().map(Self::convert_track).collect();
+    /// Converts every track and returns the messages with the duration of the song,
+    /// which ends with the last message that isn't a tempo change.
+    pub fn convert_smf(midi: &Smf) -> Result<(Vec<Vec<Self>>, Duration), MidiError> {
+        let tempo_map = TempoMap::new(midi.header.timing, &midi.tracks)?;
+        let mut messages: Vec<Vec<Self>> = midi
+            .tracks
+            .iter()
+            .map(|x| Self::convert_track(x, &tempo_map))
+            .collect::<Result<_, _>>()?;
         let mut tempo_messages: Vec<Self> = messages
             .iter()
-            .flat_map(|x| {
-                x.iter().filter(|&x| match x.kind {
-                    MsgType::Tempo(_) => true,
-                    _ => false,
-                })
-            })
+            .flat_map(|x| x.iter().filter(|&x| matches!(x.kind, MsgType::Tempo(_))))
             .copied()
             .collect();
-        tempo_messages.sort_by(|a, b| a.abs_ticks.cmp(&b.abs_ticks));
+        tempo_messages.sort_by_key(|a| a.abs_ticks);
 
         for channel in messages.iter_mut() {
             channel.extend(tempo_messages.iter());
-            channel.sort_by(|a, b| a.abs_ticks.cmp(&b.abs_ticks));
+            channel.sort_by_key(|a| a.abs_ticks);
         }
 
-        let last_message = messages
+        let has_notes = messages
             .iter()
             .flatten()
-            .max_by(|a, b| a.abs_ticks.cmp(&b.abs_ticks))
-            .unwrap();
+            .any(|x| matches!(x.kind, MsgType::NoteOn(_, _)));
+        if !has_notes {
+            return Err(MidiError::Empty);
+        }
+        let last_ticks = messages
+            .iter()
+            .flatten()
+            .filter(|x| !matches!(x.kind, MsgType::Tempo(_)))
+            .map(|x| x.abs_ticks)
+            .fold(0, max);
 
-        let duration = Self::calc_duration(&tempo_messages, last_message.abs_ticks);
+        let duration = Duration::from_secs_f64(tempo_map.seconds(last_ticks));
 
-        (messages, duration)
+        Ok((messages, duration))
     }
 
-    fn calc_duration(tempo_messages: &[Self], total_ticks: u32) -> Duration {
-        let mut tempo_i = 0;
-        let mut current_tempo = 960.0; // 120 BPM
-
-        let mut ticks = 0;
-        let mut time = 0.0;
-
-        while let Some(next_tempo_msg) = tempo_messages.get(tempo_i) {
-            let ticks_to_tempo_change = next_tempo_msg.abs_ticks - ticks;
-            let time_to_change = ticks_to_tempo_change as f64 / current_tempo;
+    /// Returns the name of the track, followed by its MIDI channel (1-16) if the track has a
+    /// MIDI channel prefix, like the parts of `split_channels`.
+    pub fn extract_track_name(track: &[TrackEvent]) -> String {
+        let name = track
+            .iter()
+            .find_map(|msg| match msg.kind {
+                TrackEventKind::Meta(MetaMessage::TrackName(name)) => {
+                    Some(String::from_utf8_lossy(name).to_string())
+                }
+                _ => None,
+            })
+            .unwrap_or("Unnamed".to_string());
+        let prefix = track.iter().find_map(|msg| match msg.kind {
+            TrackEventKind::Meta(MetaMessage::MidiChannel(channel)) => Some(channel.as_int()),
+            _ => None,
+        });
+        match prefix {
+            Some(channel) => format!("{} (ch {})", name, channel + 1),
+            None => name,
+        }
+    }
 
-            tempo_i += 1;
-            ticks += ticks_to_tempo_change;
-            time += time_to_change;
-            current_tempo = if let MsgType::Tempo(t) = next_tempo_msg.kind {
-                t
-            } else {
-                panic!("Not a tempo msg!")
+    /// Splits every track into one track per MIDI channel, ordered by channel.
+    /// Meta events are copied into every part, except tempo changes, which only go into the first part.
+    /// Every part starts with a MIDI channel prefix, so that its name includes the channel.
+    /// Tracks without channel messages are kept as they are.
+    pub fn split_channels<'a>(tracks: &[Track<'a>]) -> Result<Vec<Track<'a>>, MidiError> {
+        let mut split = Vec::new();
+        for track in tracks {
+            let mut shared = Vec::new();
+            let mut parts: Vec<(u8, Vec<(u32, TrackEventKind<'a>)>)> = Vec::new();
+            let mut abs_ticks = 0;
+            for msg in track {
+                abs_ticks = Self::add_delta(abs_ticks, msg)?;
+                match msg.kind {
+                    TrackEventKind::Midi { channel, .. } => {
+                        let channel = channel.as_int();
+                        match parts.iter_mut().find(|x| x.0 == channel) {
+                            Some(part) => part.1.push((abs_ticks, msg.kind)),
+                            None => parts.push((channel, vec![(abs_ticks, msg.kind)])),
+                        }
+                    }
+                    TrackEventKind::Meta(MetaMessage::EndOfTrack) => (),
+                    kind => shared.push((abs_ticks, kind)),
+                }
+            }
+            if parts.is_empty() {
+                split.push(track.clone());
+                continue;
+            }
+            parts.sort_by_key(|x| x.0);
+            for (i, (channel, events)) in parts.into_iter().enumerate() {
+                let prefix = TrackEventKind::Meta(MetaMessage::MidiChannel(channel.into()));
+                let mut events: Vec<(u32, TrackEventKind<'a>)> = std::iter::once((0, prefix))
+                    .chain(shared.iter().copied().filter(|(_, kind)| {
+                        i == 0 || !matches!(kind, TrackEventKind::Meta(MetaMessage::Tempo(_)))
+                    }))
+                    .chain(events)
+                    .collect();
+                events.sort_by_key(|x| x.0);
+                split.push(Self::to_track(events));
             }
         }
+        Ok(split)
+    }
 
-        let ticks_to_end = total_ticks - ticks;
-        let time_to_end = ticks_to_end as f64 / current_tempo;
+    fn to_track(events: Vec<(u32, TrackEventKind)>) -> Track {
+        let mut prev = 0;
+        let mut track: Track = events
+            .into_iter()
+            .map(|(abs_ticks, kind)| {
+                let delta = abs_ticks - prev;
+                prev = abs_ticks;
+                TrackEvent {
+                    delta: delta.into(),
+                    kind,
+                }
+            })
+            .collect();
+        track.push(TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+        });
+        track
+    }
 
-        time += time_to_end;
+    /// Returns the MIDI channel (0-15) of the first note in the track, if it has any notes.
+    pub fn extract_track_channel(track: &[TrackEvent]) -> Option<u8> {
+        track.iter().find_map(|msg| match msg.kind {
+            TrackEventKind::Midi {
+                channel,
+                message: MidiMessage::NoteOn { .. },
+            } => Some(channel.as_int()),
+            _ => None,
+        })
+    }
 
-        Duration::from_secs_f64(time)
+    /// Returns the first program change in the track, or 0 (Acoustic Grand Piano) if there is none.
+    pub fn extract_track_program(track: &[TrackEvent]) -> u8 {
+        Self::find_track_program(track).unwrap_or(0)
     }
 
-    pub fn extract_track_name(track: &[TrackEvent]) -> String {
-        for msg in track {
-            if let TrackEventKind::Meta(MetaMessage::TrackName(name)) = msg.kind {
-                return String::from_utf8_lossy(name).to_string();
-            }
-        }
-        "Unnamed".to_string()
+    /// Returns the first program change in the track, if it has one.
+    pub fn find_track_program(track: &[TrackEvent]) -> Option<u8> {
+        track.iter().find_map(|msg| match msg.kind {
+            TrackEventKind::Midi {
+                channel: _,
+                message: MidiMessage::ProgramChange { program },
+            } => Some(program.as_int()),
+            _ => None,
+        })
     }
 }
 
@@ -185,5 +409,187 @@ impl MidiMsg {
 pub enum MsgType {
     NoteOn(u8, u8),
     NoteOff(u8),
-    Tempo(f64), //Ticks Per Second
+    PitchBend(f64),  // -1..1
+    Control(u8, u8), // Controller, Value
+    ProgramChange(u8),
+    Tempo(u32), // Microseconds per beat
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midly::{Format, Fps, Header};
+
+    fn event(delta: u32, kind: TrackEventKind<'static>) -> TrackEvent<'static> {
+        TrackEvent {
+            delta: delta.into(),
+            kind,
+        }
+    }
+
+    fn note(channel: u8, key: u8, vel: u8) -> TrackEventKind<'static> {
+        TrackEventKind::Midi {
+            channel: channel.into(),
+            message: MidiMessage::NoteOn {
+                key: key.into(),
+                vel: vel.into(),
+            },
+        }
+    }
+
+    fn tempo(micros_per_beat: u32) -> TrackEventKind<'static> {
+        TrackEventKind::Meta(MetaMessage::Tempo(micros_per_beat.into()))
+    }
+
+    fn smf(timing: Timing, tracks: Vec<Track<'static>>) -> Smf<'static> {
+        Smf {
+            header: Header::new(Format::Parallel, timing),
+            tracks,
+        }
+    }
+
+    /// A single note of `beats` beats at `ticks_per_beat`.
+    fn single_note(ticks_per_beat: u16, beats: u32) -> Smf<'static> {
+        let track = vec![
+            event(0, note(0, 60, 100)),
+            event(beats * ticks_per_beat as u32, note(0, 60, 0)),
+        ];
+        smf(Timing::Metrical(ticks_per_beat.into()), vec![track])
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn metrical_timing_at_96_and_960_ppq() {
+        for ticks_per_beat in [96, 960] {
+            let midi = single_note(ticks_per_beat, 4);
+            let tempo_map = TempoMap::new(midi.header.timing, &midi.tracks).unwrap();
+            assert_close(tempo_map.seconds(ticks_per_beat as u32), 0.5);
+            let (messages, duration) = MidiMsg::convert_smf(&midi).unwrap();
+            assert_close(duration.as_secs_f64(), 2.0);
+            assert_close(messages[0][1].time(), 2.0);
+        }
+    }
+
+    #[test]
+    fn timecode_timing_ignores_tempo_changes() {
+        // 25 frames of 40 subframes are 1000 ticks per second.
+        let track = vec![
+            event(0, tempo(1_000_000)),
+            event(0, note(0, 60, 100)),
+            event(1500, note(0, 60, 0)),
+        ];
+        let midi = smf(Timing::Timecode(Fps::Fps25, 40), vec![track]);
+        let tempo_map = TempoMap::new(midi.header.timing, &midi.tracks).unwrap();
+        assert_close(tempo_map.seconds(1000), 1.0);
+        let (_, duration) = MidiMsg::convert_smf(&midi).unwrap();
+        assert_close(duration.as_secs_f64(), 1.5);
+    }
+
+    #[test]
+    fn tempo_change_in_another_track() {
+        // 120 BPM for two beats, then 60 BPM.
+        let conductor = vec![event(960, tempo(1_000_000))];
+        let notes = vec![event(0, note(0, 60, 100)), event(1440, note(0, 60, 0))];
+        let midi = smf(Timing::Metrical(480.into()), vec![conductor, notes]);
+        let tempo_map = TempoMap::new(midi.header.timing, &midi.tracks).unwrap();
+        assert_close(tempo_map.seconds(960), 1.0);
+        assert_close(tempo_map.seconds(1440), 2.0);
+        assert_close(tempo_map.beat_duration(0.5), 0.5);
+        assert_close(tempo_map.beat_duration(1.5), 1.0);
+
+        let (messages, duration) = MidiMsg::convert_smf(&midi).unwrap();
+        assert_close(duration.as_secs_f64(), 2.0);
+        // The tempo change is copied into every track.
+        assert!(messages[1]
+            .iter()
+            .any(|x| matches!(x.kind(), MsgType::Tempo(1_000_000))));
+    }
+
+    #[test]
+    fn split_format_0_track_by_channel() {
+        let track = vec![
+            event(0, TrackEventKind::Meta(MetaMessage::TrackName(b"Song"))),
+            event(0, tempo(400_000)),
+            event(0, note(9, 36, 100)),
+            event(0, note(0, 60, 100)),
+            event(480, note(0, 60, 0)),
+            event(0, note(9, 36, 0)),
+            event(0, TrackEventKind::Meta(MetaMessage::EndOfTrack)),
+        ];
+        let meta = vec![event(
+            0,
+            TrackEventKind::Meta(MetaMessage::TrackName(b"Meta")),
+        )];
+        let split = MidiMsg::split_channels(&[track, meta]).unwrap();
+
+        assert_eq!(split.len(), 3);
+        let names: Vec<String> = split
+            .iter()
+            .map(|x| MidiMsg::extract_track_name(x))
+            .collect();
+        assert_eq!(names, ["Song (ch 1)", "Song (ch 10)", "Meta"]);
+        assert_eq!(MidiMsg::extract_track_channel(&split[0]), Some(0));
+        assert_eq!(MidiMsg::extract_track_channel(&split[1]), Some(9));
+        let has_tempo = |track: &Track| {
+            track
+                .iter()
+                .any(|x| matches!(x.kind, TrackEventKind::Meta(MetaMessage::Tempo(_))))
+        };
+        assert!(has_tempo(&split[0]));
+        assert!(!has_tempo(&split[1]));
+
+        // The notes keep their position.
+        let tempo_map = TempoMap::from_tempos(480.0, &[]);
+        let drums = MidiMsg::convert_track(&split[1], &tempo_map).unwrap();
+        let times: Vec<f64> = drums
+            .iter()
+            .filter(|x| matches!(x.kind(), MsgType::NoteOn(..) | MsgType::NoteOff(_)))
+            .map(|x| x.time())
+            .collect();
+        assert_eq!(times, [0.0, 0.5]);
+    }
+
+    #[test]
+    fn midi_errors() {
+        assert!(matches!(
+            Smf::parse(b"not a midi file").map_err(MidiError::from),
+            Err(MidiError::Parse(_))
+        ));
+
+        let silent = smf(
+            Timing::Metrical(480.into()),
+            vec![vec![event(0, tempo(500_000))]],
+        );
+        assert!(matches!(
+            MidiMsg::convert_smf(&silent),
+            Err(MidiError::Empty)
+        ));
+
+        let zero_ticks = single_note(0, 1);
+        assert!(matches!(
+            MidiMsg::convert_smf(&zero_ticks),
+            Err(MidiError::UnsupportedTiming(_))
+        ));
+        let zero_subframes = smf(Timing::Timecode(Fps::Fps25, 0), Vec::new());
+        assert!(matches!(
+            TempoMap::new(zero_subframes.header.timing, &zero_subframes.tracks),
+            Err(MidiError::UnsupportedTiming(_))
+        ));
+
+        // 17 maximal delta times add up to more than `u32::MAX` ticks.
+        let mut long = vec![event(0, note(0, 60, 100))];
+        long.extend((0..17).map(|_| event(0x0FFF_FFFF, note(0, 60, 0))));
+        let long = smf(Timing::Metrical(480.into()), vec![long]);
+        assert!(matches!(
+            MidiMsg::convert_smf(&long),
+            Err(MidiError::TooLong)
+        ));
+        assert!(matches!(
+            MidiMsg::split_channels(&long.tracks),
+            Err(MidiError::TooLong)
+        ));
+    }
 }
@@ -1,11 +1,13 @@
 use crate::prelude::*;
 use fundsp::prelude::*;
 use fundsp::sound::*;
+use serde::{Deserialize, Serialize};
 
 #[derive(Clone)]
 pub struct PercussionSynth {
     midi_wrapper: MidiWrapper,
     samples: Vec<(u8, Box<dyn AudioUnit64>, f64)>,
+    sample_rate: f64,
 }
 
 impl PercussionSynth {
@@ -17,42 +19,59 @@ impl PercussionSynth {
         Self {
             midi_wrapper: MidiWrapper::new(Vec::new()),
             samples,
+            sample_rate: DEFAULT_SR,
         }
     }
     pub fn boxed(samples: Vec<(u8, Box<dyn AudioUnit64>)>) -> Box<Self> {
         Box::new(Self::new(samples))
     }
-    fn update_notes(&mut self, new: Vec<(u8, f64)>) {
-        for note in new {
-            if let Some(i) = self.samples.iter().position(|x| x.0 == note.0) {
-                self.samples[i].1.reset();
-                self.samples[i].2 = note.1
+    fn start_note(&mut self, pitch: u8, vel: f64) {
+        if let Some(i) = self.samples.iter().position(|x| x.0 == pitch) {
+            self.samples[i].1.reset();
+            self.samples[i].2 = vel
+        }
+    }
+    fn render(&mut self, output: &mut [Frame<f64, U2>]) {
+        for frame in output.iter_mut() {
+            let mut mix: Frame<f64, U2> = [0.0, 0.0].into();
+            for i in 0..self.samples.len() {
+                let vel = self.samples[i].2;
+                let voice = &mut self.samples[i].1;
+                let input = [vel];
+                let mut output: Frame<f64, U2> = [0.0, 0.0].into();
+                voice.tick(&input, &mut output);
+                mix += output;
             }
+            *frame = mix;
         }
     }
 }
 
 impl Synthesizer for PercussionSynth {
     fn set_midi(&mut self, midi: Vec<MidiMsg>) {
-        self.midi_wrapper = MidiWrapper::new(midi)
+        self.midi_wrapper = MidiWrapper::new(midi);
+        self.midi_wrapper.set_sample_rate(self.sample_rate);
     }
 
-    fn tick(&mut self, time: f64) -> Frame<f64, U2> {
-        let (_, new) = self.midi_wrapper.tick(time);
-        self.update_notes(new);
-
-        let mut mix: Frame<f64, U2> = [0.0, 0.0].into();
-        for i in 0..self.samples.len() {
-            let vel = self.samples[i].2;
-            let voice = &mut self.samples[i].1;
-            let input = [vel];
-            let mut output: Frame<f64, U2> = [0.0, 0.0].into();
-            voice.tick(&input, &mut output);
-            mix += output;
+    fn process(&mut self, start: usize, output: &mut [Frame<f64, U2>]) {
+        let mut offset = 0;
+        while offset < output.len() {
+            while let Some(msg) = self.midi_wrapper.pop(start + offset) {
+                if let MsgType::NoteOn(pitch, vel) = msg {
+                    self.start_note(pitch, vel as f64 / 127.0);
+                }
+            }
+            let end = self
+                .midi_wrapper
+                .next_sample()
+                .map_or(output.len(), |x| min(x - start, output.len()));
+            self.render(&mut output[offset..end]);
+            offset = end;
         }
-        mix
     }
     fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        self.midi_wrapper.set_sample_rate(sample_rate);
         for voice in self.samples.iter_mut() {
             voice.1.set_sample_rate(sample_rate);
         }
@@ -67,31 +86,31 @@ impl Synthesizer for PercussionSynth {
 }
 
 pub fn bass_drum(volume: f64) -> Box<dyn AudioUnit64> {
-    let sound = bassdrum(0.2, 180.0, 60.0) * fundsp::prelude::pass() >> fundsp::prelude::pan(0.0);
+    let sound = (bassdrum(0.2, 180.0, 60.0) * fundsp::prelude::pass()) >> fundsp::prelude::pan(0.0);
     Box::new(sound * volume)
 }
 
 pub fn snare_drum(volume: f64) -> Box<dyn AudioUnit64> {
-    let sound = snaredrum(0, 0.3) * fundsp::prelude::pass() >> fundsp::prelude::pan(0.0);
+    let sound = (snaredrum(0, 0.3) * fundsp::prelude::pass()) >> fundsp::prelude::pan(0.0);
     Box::new(sound * volume)
 }
 
 pub fn shaker(volume: f64) -> Box<dyn AudioUnit64> {
-    let sound = noise()
+    let sound = (noise()
         * (constant(1.0) >> make_adsr((0.01, 0.1, 0.0, 0.0)))
         * constant(0.25)
-        * fundsp::prelude::pass()
+        * fundsp::prelude::pass())
         >> highpass_hz::<f64, f64>(6000.0, 0.5)
         >> fundsp::prelude::pan(0.0)
-        >> ((pass() | pass()) & 0.2 * reverb_stereo::<f64>(10.0, 0.3));
+        >> ((pass() | pass()) & (0.2 * reverb_stereo::<f64>(10.0, 0.3)));
     Box::new(sound * volume)
 }
 
 pub fn hihat(volume: f64) -> Box<dyn AudioUnit64> {
-    let sound = noise()
+    let sound = (noise()
         * (constant(1.0) >> make_adsr((0.01, 0.1, 0.0, 0.0)))
         * constant(0.15)
-        * fundsp::prelude::pass()
+        * fundsp::prelude::pass())
         >> highpass_hz::<f64, f64>(800.0, 0.5)
         >> fundsp::prelude::pan(0.0);
     Box::new(sound * volume)
@@ -111,6 +130,8 @@ pub fn percussion(mapping: Vec<Percussion>) -> Box<dyn MidiInstrument> {
     Box::new(PercussionSynth::new(samples))
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Percussion {
     BassDrum(u8, f64),
     SnareDrum(u8, f64),
@@ -18,11 +18,13 @@ pub fn find_sample_rate() -> f64 {
     config.sample_rate().0 as f64
 }
 
+pub type PlaybackControls = (Shared<f32>, Shared<f64>, Shared<f64>);
+
 pub fn play_and_save(
     data: Vec<(f64, f64)>,
     sample_rate: f64,
     file_path: PathBuf,
-    tx: Sender<(Instant, (Shared<f32>, Shared<f64>, Shared<f64>))>,
+    tx: Sender<(Instant, PlaybackControls)>,
 ) -> Result<(), anyhow::Error> {
     let mut wave = Wave64::new(0, sample_rate);
     let (left_channel, right_channel): (Vec<f64>, Vec<f64>) = data.into_iter().unzip();
@@ -70,7 +72,6 @@ where
         .expect("No default output device");
     let config = device.default_output_config().unwrap();
     let sample_rate = config.sample_rate();
-    println!("Sample Rate: {:?}", sample_rate);
     sound.set_sample_rate(sample_rate.0 as f64);
 
     let stream = match config.sample_format() {
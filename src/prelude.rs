@@ -1,11 +1,17 @@
+pub use crate::automation::*;
 pub use crate::daw::*;
+pub use crate::general_midi::*;
 pub use crate::instrument::*;
+pub use crate::loudness::*;
 pub use crate::midi::*;
 pub use crate::percussion::*;
 pub use crate::playback::*;
 pub use crate::processor::*;
+pub use crate::project::*;
+pub use crate::report::*;
 pub use crate::score::*;
 pub use crate::synthesizer::*;
+pub use crate::wav::*;
 
 use crate::Selector;
 use crate::ADSR;
@@ -26,7 +32,7 @@ where
 pub fn violin() -> Violin {
     let envelope = (0.1, 1.5, 0.9, 0.5);
     let vibrato_env = (0.4, 0.0, 1.0, 0.5);
-    let vibrato = Vibrato::new(0.005, 5.0, vibrato_env);
+    let vibrato = Vibrato::new(0.005, 5.0, vibrato_env).with_modulation(0.02);
     Violin::new(vibrato, envelope)
 }
 
@@ -41,18 +47,73 @@ pub fn flute() -> Flute {
     Flute::new(vibrato, envelope)
 }
 
+pub fn fm() -> FM {
+    let envelope = (0.01, 0.8, 0.6, 0.3);
+    let vibrato_env = (0.4, 0.0, 1.0, 0.3);
+    let vibrato = Vibrato::new(0.003, 5.0, vibrato_env).with_modulation(0.02);
+    FM::new(vibrato, envelope, (2.0, 1.5))
+}
+
+/// A percussion kit covering the most common drums of the General MIDI percussion map.
+pub fn gm_percussion() -> Box<dyn MidiInstrument> {
+    percussion(gm_percussion_mapping())
+}
+
+pub fn gm_percussion_mapping() -> Vec<Percussion> {
+    vec![
+        Percussion::BassDrum(35, 0.4),
+        Percussion::BassDrum(36, 0.4),
+        Percussion::SnareDrum(37, 0.4),
+        Percussion::SnareDrum(38, 0.7),
+        Percussion::SnareDrum(39, 0.5),
+        Percussion::SnareDrum(40, 0.7),
+        Percussion::HiHat(42, 1.0),
+        Percussion::HiHat(44, 0.8),
+        Percussion::HiHat(46, 1.2),
+        Percussion::HiHat(49, 1.5),
+        Percussion::HiHat(51, 1.0),
+        Percussion::Shaker(69, 1.0),
+        Percussion::Shaker(70, 1.0),
+        Percussion::Shaker(82, 1.0),
+    ]
+}
+
 pub fn reverb(room_size: f64, time: f64) -> Box<dyn Processor> {
     Box::new(reverb_stereo(room_size, time))
 }
 
 pub fn distortion(smoothing: f64, hardness: f64) -> Box<dyn Processor> {
-    Box::new(shape(Shape::AdaptiveTanh(smoothing, hardness)))
+    Distortion::boxed(smoothing, hardness)
 }
 
 pub fn crush(levels: f64) -> Box<dyn Processor> {
-    Box::new(shape(Shape::Crush(levels)))
+    Crush::boxed(levels)
 }
 
 pub fn gain(factor: f64) -> Box<dyn Processor> {
-    Box::new((pass() * constant(factor)) | (pass() * constant(factor)))
+    Gain::boxed(factor)
+}
+
+pub fn compressor(threshold: f64, ratio: f64) -> Box<dyn Processor> {
+    Compressor::boxed(threshold, ratio)
+}
+
+pub fn tempo_delay(time: NoteValue, feedback: f64, mix: f64) -> Box<dyn Processor> {
+    TempoDelay::boxed(time, feedback, mix)
+}
+
+pub fn true_peak_limiter(ceiling: f64, release: f64) -> Box<dyn Processor> {
+    TruePeakLimiter::boxed(ceiling, release)
+}
+
+pub fn chorus_effect(rate: f64, depth: f64, mix: f64) -> Box<dyn Processor> {
+    ModulatedDelay::chorus(rate, depth, mix).boxed()
+}
+
+pub fn flanger_effect(rate: f64, depth: f64, mix: f64) -> Box<dyn Processor> {
+    ModulatedDelay::flanger(rate, depth, mix).boxed()
+}
+
+pub fn phaser_effect(rate: f64, depth: f64, mix: f64) -> Box<dyn Processor> {
+    Phaser::boxed(rate, depth, mix)
 }
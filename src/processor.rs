@@ -1,65 +1,1221 @@
+use std::collections::VecDeque;
+
 use dyn_clone::{clone_trait_object, DynClone};
 use fundsp::prelude::*;
+use serde::{de, Deserialize, Deserializer, Serialize};
+
+use crate::loudness::TruePeak;
+use crate::midi::TempoMap;
 
 pub trait Processor: DynClone + Send + Sync {
     fn tick(&mut self, time: f64, input: &Frame<f64, U2>) -> Frame<f64, U2>;
     fn set_sample_rate(&mut self, _sample_rate: f64) {}
     fn reset(&mut self) {}
+    /// Sets a named parameter, e.g. from an automation lane.
+    /// Returns false if the processor doesn't have the parameter.
+    fn set_parameter(&mut self, _name: &str, _value: f64) -> bool {
+        false
+    }
+    /// Receives the sidechain signal of the channel before each `tick`, if the channel has a sidechain source.
+    fn sidechain(&mut self, _key: &Frame<f64, U2>) {}
+    /// Returns the current gain reduction in dB of processors that reduce the gain, like `Compressor` and `TruePeakLimiter`.
+    fn gain_reduction(&self) -> Option<f64> {
+        None
+    }
+    /// Receives the tempo map of the song when the MIDI file is set, when the processor is added and whenever
+    /// the DAW is reset, for processors that follow the tempo.
+    fn set_tempo_map(&mut self, _tempo_map: &TempoMap) {}
+    /// Returns by how many samples the output is delayed, e.g. for lookahead. The mixer delays the
+    /// other signals by the same amount, and renders remove it, so that channels and stems stay in time.
+    fn latency(&self) -> usize {
+        0
+    }
 }
 
 clone_trait_object!(Processor);
 
-#[derive(Clone)]
-pub struct EQ {
-    lowpass: (f64, f64),
-    highpass: (f64, f64),
-    lowpass_node: Box<dyn AudioUnit64>,
-    highpass_node: Box<dyn AudioUnit64>,
+/// The shape of an `EqBand`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BandKind {
+    /// Boosts or cuts by `gain` around the frequency, with a width set by `q`.
+    Bell,
+    /// Boosts or cuts by `gain` below the frequency.
+    LowShelf,
+    /// Boosts or cuts by `gain` above the frequency.
+    HighShelf,
+    /// Removes a narrow range around the frequency.
+    Notch,
+    Lowpass,
+    Highpass,
 }
 
-impl EQ {
-    pub fn new(lowpass: (f64, f64), highpass: (f64, f64)) -> Self {
+/// A band of a parametric `EQ`. Frequencies are in Hz and gains in dB.
+/// `slope` is the steepness of lowpass and highpass bands in dB per octave, a positive multiple of 12,
+/// which is reached by chaining filters with the same `q`. Other slopes are rejected when deserializing.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EqBand {
+    pub kind: BandKind,
+    pub frequency: f64,
+    #[serde(default = "default_q")]
+    pub q: f64,
+    #[serde(default)]
+    pub gain: f64,
+    #[serde(default = "default_slope", deserialize_with = "deserialize_slope")]
+    pub slope: u32,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_q() -> f64 {
+    std::f64::consts::FRAC_1_SQRT_2
+}
+
+fn default_slope() -> u32 {
+    12
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn is_valid_slope(slope: u32) -> bool {
+    slope > 0 && slope % 12 == 0
+}
+
+fn deserialize_slope<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let slope = u32::deserialize(deserializer)?;
+    if !is_valid_slope(slope) {
+        return Err(de::Error::custom(format!(
+            "the slope must be a positive multiple of 12 dB per octave, not {}",
+            slope
+        )));
+    }
+    Ok(slope)
+}
+
+impl EqBand {
+    pub fn new(kind: BandKind, frequency: f64, q: f64, gain: f64) -> Self {
         Self {
-            lowpass,
-            highpass,
-            lowpass_node: Box::new(
-                fundsp::prelude::lowpass::<f64, f64>() | fundsp::prelude::lowpass::<f64, f64>(),
+            kind,
+            frequency,
+            q,
+            gain,
+            slope: default_slope(),
+            enabled: true,
+        }
+    }
+    /// # Panics
+    /// If `slope` is not a positive multiple of 12.
+    pub fn with_slope(mut self, slope: u32) -> Self {
+        assert!(
+            is_valid_slope(slope),
+            "the slope must be a positive multiple of 12 dB per octave, not {}",
+            slope
+        );
+        self.slope = slope;
+        self
+    }
+    /// Returns the gain of the band in dB at a frequency, even if it is disabled.
+    pub fn response(&self, frequency: f64, sample_rate: f64) -> f64 {
+        self.stages() as f64 * Biquad::new(self, sample_rate).response(frequency, sample_rate)
+    }
+    fn stages(&self) -> usize {
+        match self.kind {
+            BandKind::Lowpass | BandKind::Highpass => self.slope as usize / 12,
+            _ => 1,
+        }
+    }
+}
+
+/// Biquad coefficients from the Audio EQ Cookbook, normalized so that a0 is 1.
+#[derive(Clone, Copy, Default)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+}
+
+impl Biquad {
+    fn new(band: &EqBand, sample_rate: f64) -> Self {
+        let w = TAU * band.frequency.clamp(1.0, sample_rate * 0.49) / sample_rate;
+        let (sin, cos) = (w.sin(), w.cos());
+        let alpha = sin / (2.0 * band.q.max(1.0e-3));
+        let a = pow(10.0, band.gain / 40.0);
+        let shelf = 2.0 * a.sqrt() * alpha;
+        let (b, a) = match band.kind {
+            BandKind::Bell => (
+                [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a],
+                [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a],
+            ),
+            BandKind::LowShelf => (
+                [
+                    a * ((a + 1.0) - (a - 1.0) * cos + shelf),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                    a * ((a + 1.0) - (a - 1.0) * cos - shelf),
+                ],
+                [
+                    (a + 1.0) + (a - 1.0) * cos + shelf,
+                    -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                    (a + 1.0) + (a - 1.0) * cos - shelf,
+                ],
             ),
-            highpass_node: Box::new(
-                fundsp::prelude::highpass::<f64, f64>() | fundsp::prelude::highpass::<f64, f64>(),
+            BandKind::HighShelf => (
+                [
+                    a * ((a + 1.0) + (a - 1.0) * cos + shelf),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                    a * ((a + 1.0) + (a - 1.0) * cos - shelf),
+                ],
+                [
+                    (a + 1.0) - (a - 1.0) * cos + shelf,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                    (a + 1.0) - (a - 1.0) * cos - shelf,
+                ],
             ),
+            BandKind::Notch => (
+                [1.0, -2.0 * cos, 1.0],
+                [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            ),
+            BandKind::Lowpass => (
+                [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+                [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            ),
+            BandKind::Highpass => (
+                [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+                [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            ),
+        };
+        Self {
+            b: [b[0] / a[0], b[1] / a[0], b[2] / a[0]],
+            a: [a[1] / a[0], a[2] / a[0]],
         }
     }
+    /// Returns the gain in dB at a frequency.
+    fn response(&self, frequency: f64, sample_rate: f64) -> f64 {
+        let w = TAU * frequency / sample_rate;
+        let power = |c: [f64; 3]| {
+            let re = c[0] + c[1] * w.cos() + c[2] * (2.0 * w).cos();
+            let im = c[1] * w.sin() + c[2] * (2.0 * w).sin();
+            re * re + im * im
+        };
+        10.0 * log10(power(self.b) / power([1.0, self.a[0], self.a[1]]))
+    }
+    /// Filters a sample with the transposed direct form II state `z`.
+    fn tick(&self, z: &mut [f64; 2], x: f64) -> f64 {
+        let y = self.b[0] * x + z[0];
+        z[0] = self.b[1] * x - self.a[0] * y + z[1];
+        z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+/// A stereo parametric EQ with any number of bands, which are applied in order.
+/// Its parameters are named after the index of the band, e.g. `band0_frequency`, see `set_parameter`.
+#[derive(Clone)]
+pub struct EQ {
+    bands: Vec<EqBand>,
+    sample_rate: f64,
+    filters: Vec<Biquad>,
+    /// The state of every stage of every band, per channel.
+    states: Vec<Vec<[[f64; 2]; 2]>>,
+}
+
+impl EQ {
+    /// Creates an EQ with a lowpass and a highpass band, each given as (frequency, q).
+    pub fn new(lowpass: (f64, f64), highpass: (f64, f64)) -> Self {
+        Self::from_bands(vec![
+            EqBand::new(BandKind::Lowpass, lowpass.0, lowpass.1, 0.0),
+            EqBand::new(BandKind::Highpass, highpass.0, highpass.1, 0.0),
+        ])
+    }
     pub fn boxed(lowpass: (f64, f64), highpass: (f64, f64)) -> Box<Self> {
         Box::new(Self::new(lowpass, highpass))
     }
+    pub fn from_bands(bands: Vec<EqBand>) -> Self {
+        let mut eq = Self {
+            bands,
+            sample_rate: DEFAULT_SR,
+            filters: Vec::new(),
+            states: Vec::new(),
+        };
+        eq.update();
+        eq
+    }
+    pub fn with_band(mut self, band: EqBand) -> Self {
+        self.bands.push(band);
+        self.update();
+        self
+    }
+    pub fn bands(&self) -> &[EqBand] {
+        &self.bands
+    }
+    /// Replaces a band. Its filter state is kept, so that it can be changed while playing.
+    pub fn set_band(&mut self, index: usize, band: EqBand) {
+        self.bands[index] = band;
+        self.update_band(index);
+    }
+    /// Returns the gain of the enabled bands in dB at a frequency.
+    pub fn response(&self, frequency: f64) -> f64 {
+        self.bands
+            .iter()
+            .filter(|x| x.enabled)
+            .map(|x| x.response(frequency, self.sample_rate))
+            .sum()
+    }
+    /// Returns `points` (frequency, gain in dB) pairs with logarithmically spaced frequencies
+    /// from `from` to `to` Hz, e.g. for plotting the response.
+    pub fn response_curve(&self, from: f64, to: f64, points: usize) -> Vec<(f64, f64)> {
+        (0..points)
+            .map(|i| {
+                let t = i as f64 / max(points - 1, 1) as f64;
+                let frequency = from * pow(to / from, t);
+                (frequency, self.response(frequency))
+            })
+            .collect()
+    }
+    /// Recomputes the filters and adds or removes filter states to match the bands.
+    fn update(&mut self) {
+        self.filters = self
+            .bands
+            .iter()
+            .map(|x| Biquad::new(x, self.sample_rate))
+            .collect();
+        self.states.resize(self.bands.len(), Vec::new());
+        for (state, band) in self.states.iter_mut().zip(self.bands.iter()) {
+            state.resize(band.stages(), [[0.0; 2]; 2]);
+        }
+    }
+    /// Recomputes the filter and resizes the filter state of a single band.
+    fn update_band(&mut self, index: usize) {
+        let band = &self.bands[index];
+        self.filters[index] = Biquad::new(band, self.sample_rate);
+        self.states[index].resize(band.stages(), [[0.0; 2]; 2]);
+    }
+    /// Finds the band of a parameter name like `band0_frequency`, or the first lowpass or highpass band
+    /// for the names `lowpass_cutoff`, `lowpass_q`, `highpass_cutoff` and `highpass_q`.
+    fn find_parameter<'a>(&self, name: &'a str) -> Option<(usize, &'a str)> {
+        if let Some((index, parameter)) = name.strip_prefix("band").and_then(|x| x.split_once('_'))
+        {
+            let index = index.parse().ok().filter(|&x| x < self.bands.len())?;
+            return Some((index, parameter));
+        }
+        let (kind, parameter) = match name {
+            "lowpass_cutoff" => (BandKind::Lowpass, "frequency"),
+            "lowpass_q" => (BandKind::Lowpass, "q"),
+            "highpass_cutoff" => (BandKind::Highpass, "frequency"),
+            "highpass_q" => (BandKind::Highpass, "q"),
+            _ => return None,
+        };
+        let index = self.bands.iter().position(|x| x.kind == kind)?;
+        Some((index, parameter))
+    }
 }
 
 impl Processor for EQ {
     fn tick(&mut self, _time: f64, input: &Frame<f64, U2>) -> Frame<f64, U2> {
-        let low_in = [
-            input[0],
-            self.lowpass.0,
-            self.lowpass.1,
-            input[1],
-            self.lowpass.0,
-            self.lowpass.1,
-        ];
-        let mut output = [0.0, 0.0];
-        self.lowpass_node.tick(&low_in, &mut output);
-
-        let high_in = [
-            output[0],
-            self.highpass.0,
-            self.highpass.1,
-            output[1],
-            self.highpass.0,
-            self.highpass.1,
-        ];
-        self.highpass_node.tick(&high_in, &mut output);
+        let mut output = [input[0], input[1]];
+        for ((band, filter), states) in self
+            .bands
+            .iter()
+            .zip(self.filters.iter())
+            .zip(self.states.iter_mut())
+        {
+            if !band.enabled {
+                continue;
+            }
+            for state in states.iter_mut() {
+                for (x, z) in output.iter_mut().zip(state.iter_mut()) {
+                    *x = filter.tick(z, *x);
+                }
+            }
+        }
+        output.into()
+    }
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        self.update();
+    }
+    fn reset(&mut self) {
+        for state in self.states.iter_mut() {
+            state.fill([[0.0; 2]; 2]);
+        }
+    }
+    /// Parameters: `band<index>_frequency`, `band<index>_q`, `band<index>_gain` and `band<index>_enabled`,
+    /// which enables the band if the value is at least 0.5. For an EQ created with `new`, `lowpass_cutoff`,
+    /// `lowpass_q`, `highpass_cutoff` and `highpass_q` also work.
+    fn set_parameter(&mut self, name: &str, value: f64) -> bool {
+        let Some((index, parameter)) = self.find_parameter(name) else {
+            return false;
+        };
+        let band = &mut self.bands[index];
+        match parameter {
+            "frequency" => band.frequency = value,
+            "q" => band.q = value,
+            "gain" => band.gain = value,
+            "enabled" => band.enabled = value >= 0.5,
+            _ => return false,
+        }
+        self.update_band(index);
+        true
+    }
+}
+
+/// Adaptive tanh distortion: the input is divided by its RMS level, which is smoothed over
+/// `smoothing` seconds, multiplied by `hardness` and passed through `tanh`.
+#[derive(Clone)]
+pub struct Distortion {
+    smoothing: f64,
+    hardness: f64,
+    sample_rate: f64,
+    factor: f64,
+    state: [f64; 2],
+}
+
+impl Distortion {
+    pub fn new(smoothing: f64, hardness: f64) -> Self {
+        let mut distortion = Self {
+            smoothing,
+            hardness,
+            sample_rate: DEFAULT_SR,
+            factor: 0.0,
+            state: [0.0; 2],
+        };
+        distortion.update_factor();
+        distortion
+    }
+    pub fn boxed(smoothing: f64, hardness: f64) -> Box<Self> {
+        Box::new(Self::new(smoothing, hardness))
+    }
+    fn update_factor(&mut self) {
+        self.factor = pow(0.5, 1.0 / (self.smoothing * self.sample_rate));
+    }
+}
+
+impl Processor for Distortion {
+    fn tick(&mut self, _time: f64, input: &Frame<f64, U2>) -> Frame<f64, U2> {
+        let mut output = [0.0; 2];
+        for (i, x) in output.iter_mut().enumerate() {
+            self.state[i] =
+                self.factor * self.state[i] + (1.0 - self.factor) * (1.0e-6 + squared(input[i]));
+            *x = tanh(input[i] * self.hardness / sqrt(self.state[i]));
+        }
+        output.into()
+    }
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        self.update_factor();
+    }
+    fn reset(&mut self) {
+        self.state = [0.0; 2];
+    }
+    /// Parameters: `smoothing` and `hardness`.
+    fn set_parameter(&mut self, name: &str, value: f64) -> bool {
+        match name {
+            "smoothing" => {
+                self.smoothing = value;
+                self.update_factor();
+            }
+            "hardness" => self.hardness = value,
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// Quantizes the signal to `levels` steps per unit.
+#[derive(Clone)]
+pub struct Crush {
+    levels: f64,
+}
+
+impl Crush {
+    pub fn new(levels: f64) -> Self {
+        Self { levels }
+    }
+    pub fn boxed(levels: f64) -> Box<Self> {
+        Box::new(Self::new(levels))
+    }
+}
+
+impl Processor for Crush {
+    fn tick(&mut self, _time: f64, input: &Frame<f64, U2>) -> Frame<f64, U2> {
+        [
+            round(input[0] * self.levels) / self.levels,
+            round(input[1] * self.levels) / self.levels,
+        ]
+        .into()
+    }
+    /// Parameters: `levels`.
+    fn set_parameter(&mut self, name: &str, value: f64) -> bool {
+        match name {
+            "levels" => self.levels = value,
+            _ => return false,
+        }
+        true
+    }
+}
+
+#[derive(Clone)]
+pub struct Gain {
+    factor: f64,
+}
+
+impl Gain {
+    pub fn new(factor: f64) -> Self {
+        Self { factor }
+    }
+    pub fn boxed(factor: f64) -> Box<Self> {
+        Box::new(Self::new(factor))
+    }
+}
+
+impl Processor for Gain {
+    fn tick(&mut self, _time: f64, input: &Frame<f64, U2>) -> Frame<f64, U2> {
+        input * Frame::splat(self.factor)
+    }
+    /// Parameters: `factor`.
+    fn set_parameter(&mut self, name: &str, value: f64) -> bool {
+        match name {
+            "factor" => self.factor = value,
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// A stereo feed-forward compressor with a soft knee and optional lookahead.
+/// Levels are in dB and times in seconds. The gain reduction is computed from the louder channel,
+/// either of the input or, if `with_sidechain` is used, of the sidechain signal of the channel.
+/// Lookahead delays the output, so that the compressor can react before transients, and is reported as `latency`.
+#[derive(Clone)]
+pub struct Compressor {
+    threshold: f64,
+    ratio: f64,
+    knee: f64,
+    attack: f64,
+    release: f64,
+    makeup: f64,
+    lookahead: f64,
+    external: bool,
+    sample_rate: f64,
+    attack_factor: f64,
+    release_factor: f64,
+    /// The smoothed gain reduction in dB.
+    reduction: f64,
+    key: Frame<f64, U2>,
+    delay: VecDeque<Frame<f64, U2>>,
+}
+
+impl Compressor {
+    pub fn new(threshold: f64, ratio: f64) -> Self {
+        let mut compressor = Self {
+            threshold,
+            ratio,
+            knee: 6.0,
+            attack: 0.01,
+            release: 0.1,
+            makeup: 0.0,
+            lookahead: 0.0,
+            external: false,
+            sample_rate: DEFAULT_SR,
+            attack_factor: 0.0,
+            release_factor: 0.0,
+            reduction: 0.0,
+            key: Frame::splat(0.0),
+            delay: VecDeque::new(),
+        };
+        compressor.update();
+        compressor
+    }
+    pub fn boxed(threshold: f64, ratio: f64) -> Box<Self> {
+        Box::new(Self::new(threshold, ratio))
+    }
+    /// The width of the soft knee in dB. 0 gives a hard knee.
+    pub fn with_knee(mut self, knee: f64) -> Self {
+        self.knee = knee;
+        self
+    }
+    pub fn with_attack(mut self, attack: f64) -> Self {
+        self.attack = attack;
+        self.update();
+        self
+    }
+    pub fn with_release(mut self, release: f64) -> Self {
+        self.release = release;
+        self.update();
+        self
+    }
+    pub fn with_makeup(mut self, makeup: f64) -> Self {
+        self.makeup = makeup;
+        self
+    }
+    pub fn with_lookahead(mut self, lookahead: f64) -> Self {
+        self.lookahead = lookahead;
+        self.update();
+        self
+    }
+    /// Uses the sidechain signal of the channel instead of the input to compute the gain reduction.
+    pub fn with_sidechain(mut self) -> Self {
+        self.external = true;
+        self
+    }
+    fn update(&mut self) {
+        let factor = |time: f64| {
+            if time > 0.0 {
+                exp(-1.0 / (time * self.sample_rate))
+            } else {
+                0.0
+            }
+        };
+        self.attack_factor = factor(self.attack);
+        self.release_factor = factor(self.release);
+        let length = (self.lookahead * self.sample_rate).round() as usize;
+        self.delay.resize(length, Frame::splat(0.0));
+    }
+    /// The static gain reduction in dB for a level in dB.
+    fn gain_computer(&self, level: f64) -> f64 {
+        let over = level - self.threshold;
+        let slope = 1.0 / self.ratio - 1.0;
+        if 2.0 * over <= -self.knee {
+            0.0
+        } else if 2.0 * over.abs() < self.knee {
+            slope * squared(over + self.knee / 2.0) / (2.0 * self.knee)
+        } else {
+            slope * over
+        }
+    }
+}
+
+impl Processor for Compressor {
+    fn tick(&mut self, _time: f64, input: &Frame<f64, U2>) -> Frame<f64, U2> {
+        let key = if self.external { &self.key } else { input };
+        let level = amp_db(max(abs(key[0]), abs(key[1])).max(1e-10));
+        let target = self.gain_computer(level);
+        let factor = if target < self.reduction {
+            self.attack_factor
+        } else {
+            self.release_factor
+        };
+        self.reduction = factor * self.reduction + (1.0 - factor) * target;
+
+        let delayed = if self.delay.is_empty() {
+            *input
+        } else {
+            self.delay.push_back(*input);
+            self.delay.pop_front().unwrap_or_default()
+        };
+        delayed * Frame::splat(db_amp(self.reduction + self.makeup))
+    }
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        self.update();
+    }
+    fn reset(&mut self) {
+        self.reduction = 0.0;
+        self.key = Frame::splat(0.0);
+        self.delay.iter_mut().for_each(|x| *x = Frame::splat(0.0));
+    }
+    /// Parameters: `threshold`, `ratio`, `knee`, `attack`, `release` and `makeup`.
+    fn set_parameter(&mut self, name: &str, value: f64) -> bool {
+        match name {
+            "threshold" => self.threshold = value,
+            "ratio" => self.ratio = value,
+            "knee" => self.knee = value,
+            "attack" => self.attack = value,
+            "release" => self.release = value,
+            "makeup" => self.makeup = value,
+            _ => return false,
+        }
+        self.update();
+        true
+    }
+    fn sidechain(&mut self, key: &Frame<f64, U2>) {
+        self.key = *key;
+    }
+    fn gain_reduction(&self) -> Option<f64> {
+        Some(self.reduction)
+    }
+    fn latency(&self) -> usize {
+        self.delay.len()
+    }
+}
+
+/// A stereo brickwall limiter that keeps the true peak, measured with 4x oversampling, below `ceiling` in dBTP.
+/// The gain is reduced smoothly over the lookahead time before a peak and recovers with `release` in seconds.
+/// The output is delayed by the lookahead time plus a few samples for the peak detection, which is reported as `latency`.
+#[derive(Clone)]
+pub struct TruePeakLimiter {
+    ceiling: f64,
+    release: f64,
+    lookahead: f64,
+    sample_rate: f64,
+    release_factor: f64,
+    detectors: [TruePeak; 2],
+    /// The required gains of the hold window as (sample, gain), increasing, for a running minimum.
+    minimum: VecDeque<(usize, f64)>,
+    /// The held gains of the last lookahead samples, which are averaged to ramp the gain down.
+    ramp: VecDeque<f64>,
+    ramp_sum: f64,
+    delay: VecDeque<Frame<f64, U2>>,
+    position: usize,
+    gain: f64,
+}
+
+impl TruePeakLimiter {
+    pub fn new(ceiling: f64, release: f64) -> Self {
+        let mut limiter = Self {
+            ceiling,
+            release,
+            lookahead: 0.005,
+            sample_rate: DEFAULT_SR,
+            release_factor: 0.0,
+            detectors: [TruePeak::new(), TruePeak::new()],
+            minimum: VecDeque::new(),
+            ramp: VecDeque::new(),
+            ramp_sum: 0.0,
+            delay: VecDeque::new(),
+            position: 0,
+            gain: 1.0,
+        };
+        limiter.reset();
+        limiter
+    }
+    pub fn boxed(ceiling: f64, release: f64) -> Box<Self> {
+        Box::new(Self::new(ceiling, release))
+    }
+    pub fn with_lookahead(mut self, lookahead: f64) -> Self {
+        self.lookahead = lookahead;
+        self.reset();
+        self
+    }
+    fn lookahead_samples(&self) -> usize {
+        max((self.lookahead * self.sample_rate).round() as usize, 1)
+    }
+    fn update(&mut self) {
+        self.release_factor = if self.release > 0.0 {
+            exp(-1.0 / (self.release * self.sample_rate))
+        } else {
+            0.0
+        };
+    }
+}
+
+impl Processor for TruePeakLimiter {
+    fn tick(&mut self, _time: f64, input: &Frame<f64, U2>) -> Frame<f64, U2> {
+        let peak = max(
+            self.detectors[0].push(input[0]),
+            self.detectors[1].push(input[1]),
+        );
+        let ceiling = db_amp(self.ceiling);
+        let required = if peak > ceiling { ceiling / peak } else { 1.0 };
+
+        // Holding the minimum for the lookahead and detection time and averaging it over the lookahead
+        // ensures that the gain is low enough when a peak leaves the delay line.
+        let lookahead = self.lookahead_samples();
+        let hold = lookahead + TruePeak::LATENCY;
+        while self.minimum.back().is_some_and(|x| x.1 >= required) {
+            self.minimum.pop_back();
+        }
+        self.minimum.push_back((self.position, required));
+        while self
+            .minimum
+            .front()
+            .is_some_and(|x| x.0 + hold <= self.position)
+        {
+            self.minimum.pop_front();
+        }
+        let held = self.minimum.front().map_or(1.0, |x| x.1);
+        self.ramp.push_back(held);
+        self.ramp_sum += held - self.ramp.pop_front().unwrap_or_default();
+        let target = self.ramp_sum / lookahead as f64;
+        self.gain = if target < self.gain {
+            target
+        } else {
+            target - (target - self.gain) * self.release_factor
+        };
+        self.position += 1;
+
+        self.delay.push_back(*input);
+        let delayed = self.delay.pop_front().unwrap_or_default();
+        delayed * Frame::splat(self.gain)
+    }
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        self.reset();
+    }
+    fn reset(&mut self) {
+        self.update();
+        let lookahead = self.lookahead_samples();
+        self.detectors = [TruePeak::new(), TruePeak::new()];
+        self.minimum.clear();
+        self.ramp = vec![1.0; lookahead].into();
+        self.ramp_sum = lookahead as f64;
+        self.delay = vec![Frame::splat(0.0); lookahead - 1 + TruePeak::LATENCY].into();
+        self.position = 0;
+        self.gain = 1.0;
+    }
+    /// Parameters: `ceiling` and `release`.
+    fn set_parameter(&mut self, name: &str, value: f64) -> bool {
+        match name {
+            "ceiling" => self.ceiling = value,
+            "release" => self.release = value,
+            _ => return false,
+        }
+        self.update();
+        true
+    }
+    fn gain_reduction(&self) -> Option<f64> {
+        Some(amp_db(self.gain))
+    }
+    fn latency(&self) -> usize {
+        self.delay.len()
+    }
+}
+
+/// Changes the length of a note value.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NoteModifier {
+    #[default]
+    Straight,
+    /// One and a half times as long.
+    Dotted,
+    /// Two thirds as long, so that three fit into the time of two.
+    Triplet,
+}
+
+/// A note length that follows the tempo, e.g. an eighth note or a dotted quarter note.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NoteValue {
+    /// 1 for a whole note, 4 for a quarter note, 8 for an eighth note and so on.
+    pub division: u32,
+    #[serde(default)]
+    pub modifier: NoteModifier,
+}
+
+impl NoteValue {
+    pub fn new(division: u32, modifier: NoteModifier) -> Self {
+        Self { division, modifier }
+    }
+    /// Returns the length in beats, which are quarter notes.
+    pub fn beats(&self) -> f64 {
+        let beats = 4.0 / max(self.division, 1) as f64;
+        match self.modifier {
+            NoteModifier::Straight => beats,
+            NoteModifier::Dotted => beats * 1.5,
+            NoteModifier::Triplet => beats * 2.0 / 3.0,
+        }
+    }
+    /// Returns the length in seconds with the tempo at a time in seconds.
+    pub fn seconds(&self, tempo_map: &TempoMap, time: f64) -> f64 {
+        self.beats() * tempo_map.beat_duration(time)
+    }
+}
+
+/// A stereo delay whose time is a note value that follows the tempo map of the song.
+/// The echoes pass a lowpass and a highpass (cutoffs in Hz) in the feedback loop, so that every repeat gets duller.
+/// With ping-pong, the echoes of the mono sum of the input alternate between left and right.
+/// `mix` fades from the dry signal (0) to the echoes only (1).
+/// On tempo changes, the delay time glides to the new length instead of jumping, which would click.
+#[derive(Clone)]
+pub struct TempoDelay {
+    time: NoteValue,
+    feedback: f64,
+    mix: f64,
+    lowpass: f64,
+    highpass: f64,
+    ping_pong: bool,
+    tempo_map: TempoMap,
+    sample_rate: f64,
+    lowpass_factor: f64,
+    highpass_factor: f64,
+    /// The current delay in samples, which follows the note value with one-pole smoothing.
+    delay: f64,
+    delay_factor: f64,
+    buffers: [Vec<f64>; 2],
+    position: usize,
+    /// The states of the lowpass and of the lowpass that is subtracted for the highpass, per channel.
+    filters: [[f64; 2]; 2],
+}
+
+impl TempoDelay {
+    /// The time constant in seconds of the glide to a new delay time.
+    const GLIDE_TIME: f64 = 0.05;
+
+    pub fn new(time: NoteValue, feedback: f64, mix: f64) -> Self {
+        let mut delay = Self {
+            time,
+            feedback,
+            mix,
+            lowpass: 8000.0,
+            highpass: 80.0,
+            ping_pong: false,
+            tempo_map: TempoMap::default(),
+            sample_rate: DEFAULT_SR,
+            lowpass_factor: 0.0,
+            highpass_factor: 0.0,
+            delay: 0.0,
+            delay_factor: 0.0,
+            buffers: [Vec::new(), Vec::new()],
+            position: 0,
+            filters: [[0.0; 2]; 2],
+        };
+        delay.reset();
+        delay
+    }
+    pub fn boxed(time: NoteValue, feedback: f64, mix: f64) -> Box<Self> {
+        Box::new(Self::new(time, feedback, mix))
+    }
+    pub fn with_filter(mut self, lowpass: f64, highpass: f64) -> Self {
+        self.lowpass = lowpass;
+        self.highpass = highpass;
+        self.update();
+        self
+    }
+    pub fn with_ping_pong(mut self) -> Self {
+        self.ping_pong = true;
+        self
+    }
+    fn update(&mut self) {
+        let factor = |cutoff: f64| 1.0 - exp(-TAU * cutoff / self.sample_rate);
+        self.lowpass_factor = factor(self.lowpass);
+        self.highpass_factor = factor(self.highpass);
+        self.delay_factor = 1.0 - exp(-1.0 / (Self::GLIDE_TIME * self.sample_rate));
+    }
+    /// Returns the delay in samples that the note value has at a time in seconds.
+    fn target_delay(&self, time: f64) -> f64 {
+        max(
+            self.time.seconds(&self.tempo_map, time) * self.sample_rate,
+            1.0,
+        )
+    }
+    /// Grows the buffers to hold at least `length` samples, keeping the recent samples.
+    fn reserve(&mut self, length: usize) {
+        let old_length = self.buffers[0].len();
+        if old_length >= length {
+            return;
+        }
+        let new_length = length.next_power_of_two();
+        for buffer in self.buffers.iter_mut() {
+            let mut grown = vec![0.0; new_length];
+            for back in 1..=old_length {
+                grown[Self::index(self.position, back, new_length)] =
+                    buffer[Self::index(self.position, back, old_length)];
+            }
+            *buffer = grown;
+        }
+    }
+    fn index(position: usize, back: usize, length: usize) -> usize {
+        (position + length - back % length) % length
+    }
+    /// Reads a channel `delay` samples back with linear interpolation.
+    fn read(&self, channel: usize, delay: f64) -> f64 {
+        let buffer = &self.buffers[channel];
+        let back = delay.floor() as usize;
+        let a = buffer[Self::index(self.position, back, buffer.len())];
+        let b = buffer[Self::index(self.position, back + 1, buffer.len())];
+        a + (b - a) * (delay - back as f64)
+    }
+    fn filter(&mut self, channel: usize, x: f64) -> f64 {
+        let [lowpass, highpass] = &mut self.filters[channel];
+        *lowpass += self.lowpass_factor * (x - *lowpass);
+        *highpass += self.highpass_factor * (*lowpass - *highpass);
+        *lowpass - *highpass
+    }
+}
+
+impl Processor for TempoDelay {
+    fn tick(&mut self, time: f64, input: &Frame<f64, U2>) -> Frame<f64, U2> {
+        self.delay += self.delay_factor * (self.target_delay(time) - self.delay);
+        let delay = self.delay;
+        self.reserve(delay.ceil() as usize + 2);
+        let delayed = [self.read(0, delay), self.read(1, delay)];
+
+        let (dry, feedback) = if self.ping_pong {
+            ([(input[0] + input[1]) / 2.0, 0.0], [delayed[1], delayed[0]])
+        } else {
+            ([input[0], input[1]], delayed)
+        };
+        for channel in 0..2 {
+            let x = self.filter(channel, dry[channel] + feedback[channel] * self.feedback);
+            let length = self.buffers[channel].len();
+            self.buffers[channel][self.position % length] = x;
+        }
+        self.position += 1;
+
+        let echoes: Frame<f64, U2> = delayed.into();
+        *input * Frame::splat(1.0 - self.mix) + echoes * Frame::splat(self.mix)
+    }
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        self.reset();
+    }
+    fn reset(&mut self) {
+        self.update();
+        self.buffers.iter_mut().for_each(|x| x.fill(0.0));
+        self.position = 0;
+        self.filters = [[0.0; 2]; 2];
+        self.delay = self.target_delay(0.0);
+        self.reserve(self.delay.ceil() as usize + 2);
+    }
+    /// Parameters: `feedback`, `mix`, `lowpass` and `highpass`.
+    fn set_parameter(&mut self, name: &str, value: f64) -> bool {
+        match name {
+            "feedback" => self.feedback = value,
+            "mix" => self.mix = value,
+            "lowpass" => self.lowpass = value,
+            "highpass" => self.highpass = value,
+            _ => return false,
+        }
+        self.update();
+        true
+    }
+    fn set_tempo_map(&mut self, tempo_map: &TempoMap) {
+        self.tempo_map = tempo_map.clone();
+    }
+}
+
+/// A sine LFO for the modulation effects.
+/// The right channel is ahead of the left by `spread` times half a cycle.
+#[derive(Clone)]
+struct Lfo {
+    rate: f64,
+    spread: f64,
+    phase: f64,
+}
+
+impl Lfo {
+    fn new(rate: f64, spread: f64) -> Self {
+        Self {
+            rate,
+            spread,
+            phase: 0.0,
+        }
+    }
+    /// Returns the value of a channel, with `offset` added to the phase in cycles.
+    fn value(&self, channel: usize, offset: f64) -> f64 {
+        (TAU * (self.phase + offset + channel as f64 * self.spread / 2.0)).sin()
+    }
+    fn advance(&mut self, sample_rate: f64) {
+        self.phase = (self.phase + self.rate / sample_rate).fract();
+    }
+}
+
+/// A delay line with a fixed length and interpolated reads.
+#[derive(Clone)]
+struct DelayLine {
+    buffer: Vec<f64>,
+    position: usize,
+}
+
+impl DelayLine {
+    fn new(length: usize) -> Self {
+        Self {
+            buffer: vec![0.0; max(length, 2)],
+            position: 0,
+        }
+    }
+    fn write(&mut self, x: f64) {
+        self.buffer[self.position] = x;
+        self.position = (self.position + 1) % self.buffer.len();
+    }
+    /// Reads the signal `delay` samples back, between one sample and the length of the line.
+    fn read(&self, delay: f64) -> f64 {
+        let length = self.buffer.len();
+        let delay = delay.clamp(1.0, (length - 1) as f64);
+        let back = delay.floor() as usize;
+        let a = self.buffer[(self.position + length - back) % length];
+        let b = self.buffer[(self.position + length - min(back + 1, length)) % length];
+        a + (b - a) * (delay - back as f64)
+    }
+    fn clear(&mut self) {
+        self.buffer.fill(0.0);
+    }
+}
+
+/// A stereo chorus or flanger, which mixes a sound with copies read from a modulated delay line.
+/// Each voice reads the line at a base delay plus up to a range times `depth`, following the LFO
+/// with an evenly spread phase.
+/// `rate` is the LFO frequency in Hz, `depth` scales the modulation from 0 to 1,
+/// and `mix` fades from the dry signal (0) to the voices only (1).
+/// `spread` offsets the LFO of the right channel by up to half a cycle.
+#[derive(Clone)]
+pub struct ModulatedDelay {
+    delay: f64,
+    range: f64,
+    voices: usize,
+    depth: f64,
+    feedback: f64,
+    mix: f64,
+    lfo: Lfo,
+    sample_rate: f64,
+    lines: [DelayLine; 2],
+}
+
+impl ModulatedDelay {
+    fn new(delay: f64, range: f64, voices: usize, rate: f64, depth: f64, mix: f64) -> Self {
+        let mut modulation = Self {
+            delay,
+            range,
+            voices,
+            depth,
+            feedback: 0.0,
+            mix,
+            lfo: Lfo::new(rate, 0.0),
+            sample_rate: DEFAULT_SR,
+            lines: [DelayLine::new(0), DelayLine::new(0)],
+        };
+        modulation.set_sample_rate(DEFAULT_SR);
+        modulation
+    }
+    /// A chorus with three voices, which thickens a sound by mixing it with slightly detuned copies.
+    /// The voices are delayed by 15 ms plus up to 10 ms at `depth` 1.
+    pub fn chorus(rate: f64, depth: f64, mix: f64) -> Self {
+        Self::new(0.015, 0.01, 3, rate, depth, mix).with_spread(0.5)
+    }
+    /// A flanger, which sweeps comb filter notches through a sound with a short delay of
+    /// 0.5 ms plus up to 5 ms at `depth` 1. The feedback defaults to 0.5 for a more pronounced sweep.
+    pub fn flanger(rate: f64, depth: f64, mix: f64) -> Self {
+        Self::new(0.0005, 0.005, 1, rate, depth, mix)
+            .with_feedback(0.5)
+            .with_spread(0.25)
+    }
+    /// Boxes a chorus or flanger, e.g. `ModulatedDelay::chorus(0.8, 0.5, 0.4).boxed()`.
+    pub fn boxed(self) -> Box<Self> {
+        Box::new(self)
+    }
+    pub fn with_feedback(mut self, feedback: f64) -> Self {
+        self.feedback = feedback;
+        self
+    }
+    pub fn with_spread(mut self, spread: f64) -> Self {
+        self.lfo.spread = spread;
+        self
+    }
+}
 
+impl Processor for ModulatedDelay {
+    fn tick(&mut self, _time: f64, input: &Frame<f64, U2>) -> Frame<f64, U2> {
+        let mut output = [0.0; 2];
+        for (channel, x) in output.iter_mut().enumerate() {
+            let mut wet = 0.0;
+            for voice in 0..self.voices {
+                let modulation =
+                    (1.0 + self.lfo.value(channel, voice as f64 / self.voices as f64)) / 2.0;
+                let delay = self.delay + self.range * self.depth.clamp(0.0, 1.0) * modulation;
+                wet += self.lines[channel].read(delay * self.sample_rate);
+            }
+            wet /= self.voices as f64;
+            self.lines[channel].write(input[channel] + wet * self.feedback);
+            *x = input[channel] * (1.0 - self.mix) + wet * self.mix;
+        }
+        self.lfo.advance(self.sample_rate);
         output.into()
     }
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        let length = ((self.delay + self.range) * sample_rate).ceil() as usize + 2;
+        self.lines = [DelayLine::new(length), DelayLine::new(length)];
+    }
+    fn reset(&mut self) {
+        self.lfo.phase = 0.0;
+        self.lines.iter_mut().for_each(DelayLine::clear);
+    }
+    /// Parameters: `rate`, `depth`, `feedback`, `mix` and `spread`.
+    fn set_parameter(&mut self, name: &str, value: f64) -> bool {
+        match name {
+            "rate" => self.lfo.rate = value,
+            "depth" => self.depth = value,
+            "feedback" => self.feedback = value,
+            "mix" => self.mix = value,
+            "spread" => self.lfo.spread = value,
+            _ => return false,
+        }
+        true
+    }
+}
+
+const PHASER_STAGES: usize = 6;
+
+/// A stereo phaser with six allpass stages, whose frequency is swept around 800 Hz by up to
+/// two octaves in each direction at `depth` 1. The other parameters are the same as for `ModulatedDelay`.
+#[derive(Clone)]
+pub struct Phaser {
+    depth: f64,
+    feedback: f64,
+    mix: f64,
+    lfo: Lfo,
+    sample_rate: f64,
+    /// The previous input and output of every stage, per channel.
+    stages: [[(f64, f64); PHASER_STAGES]; 2],
+    last: [f64; 2],
+}
+
+impl Phaser {
+    pub fn new(rate: f64, depth: f64, mix: f64) -> Self {
+        Self {
+            depth,
+            feedback: 0.5,
+            mix,
+            lfo: Lfo::new(rate, 0.25),
+            sample_rate: DEFAULT_SR,
+            stages: [[(0.0, 0.0); PHASER_STAGES]; 2],
+            last: [0.0; 2],
+        }
+    }
+    pub fn boxed(rate: f64, depth: f64, mix: f64) -> Box<Self> {
+        Box::new(Self::new(rate, depth, mix))
+    }
+    pub fn with_feedback(mut self, feedback: f64) -> Self {
+        self.feedback = feedback;
+        self
+    }
+    pub fn with_spread(mut self, spread: f64) -> Self {
+        self.lfo.spread = spread;
+        self
+    }
+}
+
+impl Processor for Phaser {
+    fn tick(&mut self, _time: f64, input: &Frame<f64, U2>) -> Frame<f64, U2> {
+        let mut output = [0.0; 2];
+        for (channel, y) in output.iter_mut().enumerate() {
+            let octaves = 2.0 * self.depth.clamp(0.0, 1.0) * self.lfo.value(channel, 0.0);
+            let frequency = min(800.0 * pow(2.0, octaves), self.sample_rate * 0.45);
+            let t = tan(PI * frequency / self.sample_rate);
+            let a = (t - 1.0) / (t + 1.0);
+            let mut x = input[channel] + self.last[channel] * self.feedback;
+            for (x1, y1) in self.stages[channel].iter_mut() {
+                let out = a * x + *x1 - a * *y1;
+                *x1 = x;
+                *y1 = out;
+                x = out;
+            }
+            self.last[channel] = x;
+            *y = input[channel] * (1.0 - self.mix) + x * self.mix;
+        }
+        self.lfo.advance(self.sample_rate);
+        output.into()
+    }
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+    }
+    fn reset(&mut self) {
+        self.lfo.phase = 0.0;
+        self.stages = [[(0.0, 0.0); PHASER_STAGES]; 2];
+        self.last = [0.0; 2];
+    }
+    /// Parameters: `rate`, `depth`, `feedback`, `mix` and `spread`.
+    fn set_parameter(&mut self, name: &str, value: f64) -> bool {
+        match name {
+            "rate" => self.lfo.rate = value,
+            "depth" => self.depth = value,
+            "feedback" => self.feedback = value,
+            "mix" => self.mix = value,
+            "spread" => self.lfo.spread = value,
+            _ => return false,
+        }
+        true
+    }
 }
 
 impl<T> Processor for T
@@ -0,0 +1,446 @@
+use std::path::{Path, PathBuf};
+
+use midly::Smf;
+use serde::{Deserialize, Serialize};
+
+use crate::prelude::*;
+
+/// A song setup that can be stored as a TOML or JSON file and loaded into a `DAW`.
+/// Channels are assigned to the MIDI tracks in order. If `auto_instruments` is set,
+/// tracks without a channel get an instrument based on their General MIDI program.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Project {
+    /// The MIDI file, relative to the project file.
+    pub midi: PathBuf,
+    #[serde(default)]
+    pub split_channels: bool,
+    #[serde(default = "default_true")]
+    pub auto_instruments: bool,
+    #[serde(default)]
+    pub channels: Vec<ChannelDescription>,
+    /// Buses in the order in which they are mixed. A bus can only be routed into buses after it.
+    #[serde(default)]
+    pub buses: Vec<BusDescription>,
+    #[serde(default)]
+    pub master: Vec<ProcessorDescription>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ChannelDescription {
+    /// Overrides the name of the MIDI track, if set.
+    pub name: Option<String>,
+    pub instrument: InstrumentDescription,
+    #[serde(default = "default_volume")]
+    pub volume: f64,
+    #[serde(default)]
+    pub pan: f64,
+    /// Replaces the default processors of the instrument, if set.
+    pub processors: Option<Vec<ProcessorDescription>>,
+    /// The name of the bus the channel is routed into instead of the master.
+    pub bus: Option<String>,
+    #[serde(default)]
+    pub sends: Vec<SendDescription>,
+    #[serde(default)]
+    pub mute: bool,
+    #[serde(default)]
+    pub solo: bool,
+    #[serde(default)]
+    pub automation: Vec<AutomationLane>,
+    /// The name of the channel whose output feeds the sidechain of the processors.
+    pub sidechain: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BusDescription {
+    pub name: String,
+    #[serde(default = "default_volume")]
+    pub volume: f64,
+    #[serde(default)]
+    pub pan: f64,
+    #[serde(default)]
+    pub processors: Vec<ProcessorDescription>,
+    /// The name of the bus this bus is routed into instead of the master.
+    pub bus: Option<String>,
+    #[serde(default)]
+    pub sends: Vec<SendDescription>,
+    #[serde(default)]
+    pub mute: bool,
+    #[serde(default)]
+    pub automation: Vec<AutomationLane>,
+    /// The name of the channel whose output feeds the sidechain of the processors.
+    pub sidechain: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SendDescription {
+    /// The name of the bus.
+    pub bus: String,
+    #[serde(default = "default_volume")]
+    pub level: f64,
+    #[serde(default)]
+    pub pre_fader: bool,
+}
+
+impl SendDescription {
+    fn build(&self, buses: &[BusDescription]) -> Result<AuxSend, anyhow::Error> {
+        Ok(AuxSend::new(
+            find_bus(buses, &self.bus)?,
+            self.level,
+            self.pre_fader,
+        ))
+    }
+}
+
+fn find_bus(buses: &[BusDescription], name: &str) -> Result<usize, anyhow::Error> {
+    buses
+        .iter()
+        .position(|x| x.name == name)
+        .ok_or_else(|| anyhow::anyhow!("The project has no bus named \"{}\".", name))
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum InstrumentDescription {
+    Violin(Violin),
+    Flute(Flute),
+    Piano(Piano),
+    #[serde(rename = "fm")]
+    FM(FM),
+    Percussion {
+        #[serde(default = "gm_percussion_mapping")]
+        mapping: Vec<Percussion>,
+    },
+    None,
+}
+
+impl InstrumentDescription {
+    pub fn build(&self) -> Box<dyn MidiInstrument> {
+        match self {
+            Self::Violin(x) => Box::new(x.clone()),
+            Self::Flute(x) => Box::new(x.clone()),
+            Self::Piano(x) => Box::new(x.clone()),
+            Self::FM(x) => Box::new(x.clone()),
+            Self::Percussion { mapping } => percussion(mapping.clone()),
+            Self::None => Box::new(SinkSynth),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProcessorDescription {
+    /// See `EQ`. `lowpass` and `highpass` are (frequency, q) and come before the other bands.
+    #[serde(rename = "eq")]
+    EQ {
+        lowpass: Option<(f64, f64)>,
+        highpass: Option<(f64, f64)>,
+        #[serde(default)]
+        bands: Vec<EqBand>,
+    },
+    Reverb {
+        room_size: f64,
+        time: f64,
+    },
+    Distortion {
+        smoothing: f64,
+        hardness: f64,
+    },
+    Crush {
+        levels: f64,
+    },
+    Gain {
+        factor: f64,
+    },
+    /// See `Compressor`. If `sidechain` is set, it is keyed by the sidechain of the channel.
+    Compressor {
+        threshold: f64,
+        ratio: f64,
+        #[serde(default = "default_knee")]
+        knee: f64,
+        #[serde(default = "default_attack")]
+        attack: f64,
+        #[serde(default = "default_release")]
+        release: f64,
+        #[serde(default)]
+        makeup: f64,
+        #[serde(default)]
+        lookahead: f64,
+        #[serde(default)]
+        sidechain: bool,
+    },
+    /// See `TempoDelay`.
+    Delay {
+        time: NoteValue,
+        feedback: f64,
+        mix: f64,
+        #[serde(default)]
+        ping_pong: bool,
+        #[serde(default = "default_delay_lowpass")]
+        lowpass: f64,
+        #[serde(default = "default_delay_highpass")]
+        highpass: f64,
+    },
+    Chorus(ModulationDescription),
+    Flanger(ModulationDescription),
+    Phaser(ModulationDescription),
+    /// See `TruePeakLimiter`, meant for the master.
+    Limiter {
+        #[serde(default = "default_ceiling")]
+        ceiling: f64,
+        #[serde(default = "default_release")]
+        release: f64,
+        #[serde(default = "default_lookahead")]
+        lookahead: f64,
+    },
+}
+
+impl ProcessorDescription {
+    pub fn build(&self) -> Box<dyn Processor> {
+        match *self {
+            Self::EQ {
+                lowpass,
+                highpass,
+                ref bands,
+            } => {
+                let mut all = Vec::new();
+                if let Some((frequency, q)) = lowpass {
+                    all.push(EqBand::new(BandKind::Lowpass, frequency, q, 0.0));
+                }
+                if let Some((frequency, q)) = highpass {
+                    all.push(EqBand::new(BandKind::Highpass, frequency, q, 0.0));
+                }
+                all.extend(bands.iter().copied());
+                Box::new(EQ::from_bands(all))
+            }
+            Self::Reverb { room_size, time } => reverb(room_size, time),
+            Self::Distortion {
+                smoothing,
+                hardness,
+            } => distortion(smoothing, hardness),
+            Self::Crush { levels } => crush(levels),
+            Self::Gain { factor } => gain(factor),
+            Self::Compressor {
+                threshold,
+                ratio,
+                knee,
+                attack,
+                release,
+                makeup,
+                lookahead,
+                sidechain,
+            } => {
+                let compressor = Compressor::new(threshold, ratio)
+                    .with_knee(knee)
+                    .with_attack(attack)
+                    .with_release(release)
+                    .with_makeup(makeup)
+                    .with_lookahead(lookahead);
+                if sidechain {
+                    Box::new(compressor.with_sidechain())
+                } else {
+                    Box::new(compressor)
+                }
+            }
+            Self::Delay {
+                time,
+                feedback,
+                mix,
+                ping_pong,
+                lowpass,
+                highpass,
+            } => {
+                let delay = TempoDelay::new(time, feedback, mix).with_filter(lowpass, highpass);
+                if ping_pong {
+                    Box::new(delay.with_ping_pong())
+                } else {
+                    Box::new(delay)
+                }
+            }
+            Self::Chorus(x) => x.apply(chorus_effect(x.rate, x.depth, x.mix)),
+            Self::Flanger(x) => x.apply(flanger_effect(x.rate, x.depth, x.mix)),
+            Self::Phaser(x) => x.apply(phaser_effect(x.rate, x.depth, x.mix)),
+            Self::Limiter {
+                ceiling,
+                release,
+                lookahead,
+            } => Box::new(TruePeakLimiter::new(ceiling, release).with_lookahead(lookahead)),
+        }
+    }
+}
+
+/// The parameters of the chorus, the flanger and the phaser.
+/// Feedback and spread use the defaults of the effect if they are not set.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct ModulationDescription {
+    pub rate: f64,
+    pub depth: f64,
+    pub mix: f64,
+    pub feedback: Option<f64>,
+    pub spread: Option<f64>,
+}
+
+impl ModulationDescription {
+    fn apply(&self, mut processor: Box<dyn Processor>) -> Box<dyn Processor> {
+        for (name, value) in [("feedback", self.feedback), ("spread", self.spread)] {
+            if let Some(value) = value {
+                processor.set_parameter(name, value);
+            }
+        }
+        processor
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_volume() -> f64 {
+    1.0
+}
+
+fn default_knee() -> f64 {
+    6.0
+}
+
+fn default_attack() -> f64 {
+    0.01
+}
+
+fn default_release() -> f64 {
+    0.1
+}
+
+fn default_delay_lowpass() -> f64 {
+    8000.0
+}
+
+fn default_delay_highpass() -> f64 {
+    80.0
+}
+
+fn default_ceiling() -> f64 {
+    -1.0
+}
+
+fn default_lookahead() -> f64 {
+    0.005
+}
+
+/// Finds a channel of the DAW by name, after the names of the MIDI tracks have been assigned.
+fn find_channel(daw: &DAW, name: &str) -> Result<usize, anyhow::Error> {
+    (0..daw.channel_count)
+        .find(|&i| daw[i].name == name)
+        .ok_or_else(|| anyhow::anyhow!("The DAW has no channel named \"{}\".", name))
+}
+
+impl Project {
+    /// Loads a project from a JSON file if the extension is `.json`, and from a TOML file otherwise.
+    /// The MIDI path is resolved relative to the directory of the project file.
+    pub fn load<P>(path: P) -> Result<Self, anyhow::Error>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)?;
+        let mut project: Self = match path.extension().and_then(|x| x.to_str()) {
+            Some("json") => serde_json::from_str(&text)?,
+            _ => toml::from_str(&text)?,
+        };
+        if let Some(dir) = path.parent() {
+            project.midi = dir.join(&project.midi);
+        }
+        Ok(project)
+    }
+    pub fn save<P>(&self, path: P) -> Result<(), anyhow::Error>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let text = match path.extension().and_then(|x| x.to_str()) {
+            Some("json") => serde_json::to_string_pretty(self)?,
+            _ => toml::to_string_pretty(self)?,
+        };
+        std::fs::write(path, text)?;
+        Ok(())
+    }
+    /// Reads the MIDI file and builds a `DAW` with all channels and the master chain.
+    pub fn build(&self) -> Result<DAW, anyhow::Error> {
+        Ok(self.build_with_report()?.0)
+    }
+    /// Like `build`, but also returns how the tracks were assigned to the channels.
+    pub fn build_with_report(&self) -> Result<(DAW, MidiReport), anyhow::Error> {
+        let bytes = std::fs::read(&self.midi)?;
+        let mut midi = Smf::parse(&bytes).map_err(MidiError::from)?;
+        if self.split_channels {
+            midi.tracks = MidiMsg::split_channels(&midi.tracks)?;
+        }
+
+        let mut daw = DAW::new();
+        for channel in self.channels.iter() {
+            let name = channel.name.clone().unwrap_or("Unnamed".to_string());
+            let instrument = channel.instrument.build();
+            let processors = match &channel.processors {
+                Some(processors) => processors.iter().map(|x| x.build()).collect(),
+                None => instrument.build_processors(),
+            };
+            daw.add_channel_boxed(
+                name,
+                instrument.build_synth(),
+                processors,
+                channel.volume,
+                channel.pan,
+            );
+        }
+        for bus in self.buses.iter() {
+            let processors = bus.processors.iter().map(|x| x.build()).collect();
+            let index = daw.add_bus(bus.name.clone(), processors, bus.volume, bus.pan);
+            daw.buses[index].mute = bus.mute;
+            for lane in bus.automation.iter() {
+                daw.buses[index].add_automation(lane.clone())?;
+            }
+        }
+        for (i, channel) in self.channels.iter().enumerate() {
+            daw[i].mute = channel.mute;
+            daw[i].solo = channel.solo;
+            for lane in channel.automation.iter() {
+                daw[i].add_automation(lane.clone())?;
+            }
+            if let Some(bus) = &channel.bus {
+                daw.route(i, find_bus(&self.buses, bus)?)?;
+            }
+            for send in channel.sends.iter() {
+                daw.add_send(i, send.build(&self.buses)?)?;
+            }
+        }
+        for (i, bus) in self.buses.iter().enumerate() {
+            if let Some(target) = &bus.bus {
+                daw.route_bus(i, find_bus(&self.buses, target)?)?;
+            }
+            for send in bus.sends.iter() {
+                daw.add_bus_send(i, send.build(&self.buses)?)?;
+            }
+        }
+        daw.master.processors = self.master.iter().map(|x| x.build()).collect();
+        if self.auto_instruments {
+            daw.gm_mapping = Some(GMMapping::default());
+        }
+
+        let report = daw.set_midi(midi)?;
+        for (i, channel) in self.channels.iter().enumerate() {
+            if let Some(name) = &channel.name {
+                daw[i].name = name.clone();
+            }
+        }
+        for (i, channel) in self.channels.iter().enumerate() {
+            if let Some(source) = &channel.sidechain {
+                daw.set_sidechain(i, find_channel(&daw, source)?)?;
+            }
+        }
+        for (i, bus) in self.buses.iter().enumerate() {
+            if let Some(source) = &bus.sidechain {
+                daw.set_bus_sidechain(i, find_channel(&daw, source)?)?;
+            }
+        }
+        Ok((daw, report))
+    }
+}
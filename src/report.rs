@@ -0,0 +1,148 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use fundsp::prelude::amp_db;
+
+use crate::loudness::Loudness;
+
+/// Receives human-readable messages about what the DAW is doing.
+/// Nothing is printed unless a logger is set, e.g. `DAW::logger`.
+pub trait Logger: Send + Sync {
+    fn log(&self, message: &str);
+}
+
+impl<F> Logger for F
+where
+    F: Fn(&str) + Send + Sync,
+{
+    fn log(&self, message: &str) {
+        self(message)
+    }
+}
+
+/// Writes every message as a line to stderr.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StderrLogger;
+
+impl Logger for StderrLogger {
+    fn log(&self, message: &str) {
+        eprintln!("{}", message);
+    }
+}
+
+pub(crate) fn log_to(logger: &Option<Arc<dyn Logger>>, message: impl FnOnce() -> String) {
+    if let Some(logger) = logger {
+        logger.log(&message());
+    }
+}
+
+/// Describes how the tracks of a MIDI file were assigned to the channels of a DAW by `DAW::set_midi`.
+#[derive(Clone, Debug, Default)]
+pub struct MidiReport {
+    pub tracks: Vec<TrackReport>,
+    /// Tracks that contain notes but have no channel, so they are not rendered.
+    pub unmatched_tracks: Vec<usize>,
+    /// Channels that have no track and stay silent.
+    pub unused_channels: Vec<usize>,
+    /// The time of the last MIDI message.
+    pub duration: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct TrackReport {
+    pub name: String,
+    /// The MIDI channel of the first note, if the track has notes.
+    pub midi_channel: Option<u8>,
+    /// The first program change of the track, if it has one.
+    pub program: Option<u8>,
+    /// The DAW channel that plays this track.
+    pub channel: Option<usize>,
+    /// Whether the channel was created from the General MIDI mapping.
+    pub auto_created: bool,
+}
+
+impl MidiReport {
+    /// Returns a human-readable summary with one line per track.
+    pub fn summary(&self) -> String {
+        let mut lines = vec![format!(
+            "MIDI has {} tracks and a duration of {:.2} seconds.",
+            self.tracks.len(),
+            self.duration.as_secs_f64()
+        )];
+        for (i, track) in self.tracks.iter().enumerate() {
+            let channel = match (track.channel, track.auto_created) {
+                (Some(channel), false) => format!("channel {}", channel),
+                (Some(channel), true) => format!("channel {} (created)", channel),
+                (None, _) => "no channel".to_string(),
+            };
+            lines.push(format!("Track {} \"{}\": {}", i, track.name, channel));
+        }
+        if !self.unmatched_tracks.is_empty() {
+            lines.push(format!(
+                "Tracks with notes but no channel: {:?}",
+                self.unmatched_tracks
+            ));
+        }
+        if !self.unused_channels.is_empty() {
+            lines.push(format!(
+                "Channels without a track: {:?}",
+                self.unused_channels
+            ));
+        }
+        lines.join("\n")
+    }
+}
+
+/// Describes a finished render.
+#[derive(Clone, Debug, Default)]
+pub struct RenderReport {
+    /// The length of the song without its tail.
+    pub song_duration: Duration,
+    /// The number of rendered samples of the master, including the tail.
+    pub length: usize,
+    pub render_time: Duration,
+    /// The largest absolute sample value of the master before normalization.
+    pub peak: f64,
+    /// The number of master samples that exceeded full scale, or that were clipped when writing them.
+    pub clipped: usize,
+    /// The gain reduction in dB of the master processors, e.g. of a `TruePeakLimiter`,
+    /// as the largest reduction of every `BLOCK_SIZE` samples of the master.
+    /// Empty if no master processor reduces the gain.
+    pub gain_reduction: Vec<f64>,
+}
+
+impl RenderReport {
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "Rendered {:.2} seconds in {:.2} seconds, peak {:.1} dBFS, {} samples clipped.",
+            self.song_duration.as_secs_f64(),
+            self.render_time.as_secs_f64(),
+            amp_db(self.peak),
+            self.clipped
+        );
+        if !self.gain_reduction.is_empty() {
+            summary += &format!(
+                " Master gain reduction up to {:.1} dB.",
+                self.max_gain_reduction().abs()
+            );
+        }
+        summary
+    }
+    /// Returns the largest gain reduction of the master processors as a negative number of dB, or zero.
+    pub fn max_gain_reduction(&self) -> f64 {
+        self.gain_reduction.iter().copied().fold(0.0, f64::min)
+    }
+}
+
+/// Describes how `RenderedAudio::master_wave_with_report` normalized the master.
+#[derive(Clone, Debug, Default)]
+pub struct NormalizationReport {
+    /// The gain that was applied to the master.
+    pub gain: f64,
+    /// The largest absolute sample value before normalization.
+    pub peak: f64,
+    /// The loudness before normalization, if the master was normalized to a loudness.
+    pub loudness: Option<Loudness>,
+    /// The number of samples that were clipped to full scale after applying the gain.
+    pub clipped: usize,
+}
@@ -11,7 +11,7 @@ impl<const N: usize> Score<N> {
     pub fn from_sections(sections: Vec<Section<N>>) -> Self {
         Self { sections }
     }
-    pub fn to_midi(&self) -> Smf {
+    pub fn to_midi(&self) -> Smf<'_> {
         let mut smf = Smf::new(Header::new(
             midly::Format::SingleTrack,
             midly::Timing::Metrical(480.into()),
@@ -25,7 +25,7 @@ impl<const N: usize> Score<N> {
                 .iter()
                 .for_each(|x| x.to_midi(&mut smf, i, &mut ticks));
 
-            smf.tracks[i].sort_by(|a, b| a.delta.cmp(&b.delta));
+            smf.tracks[i].sort_by_key(|a| a.delta);
             let mut prev = 0;
             for j in 0..smf.tracks[i].len() {
                 let absolute = smf.tracks[i][j].delta.as_int();
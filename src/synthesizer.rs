@@ -6,9 +6,10 @@ use rand::prelude::*;
 /// A synthesizer is a node that takes MIDI messages and produces audio.
 /// A simple implementation is provided as `SimpleSynth`, but you can create your own.
 /// See `MidiWrapper` for a simple way to handle MIDI messages in your implementation.
+/// Audio is rendered in blocks: `process` fills `output` with the samples starting at sample `start`.
 pub trait Synthesizer: DynClone + Send + Sync {
     fn set_midi(&mut self, midi: Vec<MidiMsg>);
-    fn tick(&mut self, time: f64) -> Frame<f64, U2>;
+    fn process(&mut self, start: usize, output: &mut [Frame<f64, U2>]);
     fn set_sample_rate(&mut self, _sample_rate: f64) {}
     fn reset(&mut self) {}
 }
@@ -21,33 +22,66 @@ pub struct SinkSynth;
 impl Synthesizer for SinkSynth {
     fn set_midi(&mut self, _midi: Vec<MidiMsg>) {}
 
-    fn tick(&mut self, _time: f64) -> Frame<f64, U2> {
-        [0.0, 0.0].into()
+    fn process(&mut self, _start: usize, output: &mut [Frame<f64, U2>]) {
+        output.fill([0.0, 0.0].into());
     }
 }
 
 #[derive(Clone)]
-pub struct SineSynth;
+pub struct SineSynth {
+    sample_rate: f64,
+}
+
+impl SineSynth {
+    pub fn new() -> Self {
+        Self {
+            sample_rate: DEFAULT_SR,
+        }
+    }
+}
+
+impl Default for SineSynth {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Synthesizer for SineSynth {
     fn set_midi(&mut self, _midi: Vec<MidiMsg>) {}
 
-    fn tick(&mut self, time: f64) -> Frame<f64, U2> {
-        let val = (time * 440.0 * TAU).sin();
-        [val, val].into()
+    fn process(&mut self, start: usize, output: &mut [Frame<f64, U2>]) {
+        for (i, frame) in output.iter_mut().enumerate() {
+            let time = (start + i) as f64 / self.sample_rate;
+            let val = (time * 440.0 * TAU).sin();
+            *frame = [val, val].into();
+        }
+    }
+    fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
     }
 }
 
 /// A basic synthesizer implementation that plays notes using a fixed number of voices.
 /// The voices are cycled through, so if you have 8 voices and play 9 notes, the least recent note will be dropped.
-/// Each voice receives three inputs: frequency, velocity (0..1), and adsr control (-1 or 1).
+/// Each voice receives four inputs: frequency, velocity (0..1), adsr control (-1 or 1) and modulation wheel (0..1).
+/// Pitch bend messages shift the frequency of every voice by up to `bend_range` semitones.
+/// While the sustain pedal (CC64) is down, released notes keep sounding until the pedal is lifted.
+/// The sostenuto pedal (CC66) does the same, but only for the notes held when it was pressed.
 #[derive(Clone)]
 pub struct SimpleSynth {
     midi_wrapper: MidiWrapper,
     voices: Vec<Box<dyn AudioUnit64>>,
     last_notes: Vec<(u8, f64, bool)>,
+    held: Vec<(bool, bool)>, // Released while pedaled, Caught by sostenuto
+    sustain: bool,
+    sostenuto: bool,
     voice_index: usize,
     detunes: Vec<f64>,
     rng: StdRng,
+    pitch_bend: f64,
+    bend_range: f64,
+    modulation: f64,
+    sample_rate: f64,
 }
 
 impl SimpleSynth {
@@ -56,58 +90,131 @@ impl SimpleSynth {
             midi_wrapper: MidiWrapper::new(Vec::new()),
             voices: vec![node; voices],
             last_notes: vec![(0, 0.0, false); voices],
+            held: vec![(false, false); voices],
+            sustain: false,
+            sostenuto: false,
             voice_index: 0,
             detunes: vec![0.0; voices],
             rng: StdRng::seed_from_u64(0),
+            pitch_bend: 0.0,
+            bend_range: 2.0,
+            modulation: 0.0,
+            sample_rate: DEFAULT_SR,
         }
     }
     pub fn boxed(voices: usize, node: Box<dyn AudioUnit64>) -> Box<Self> {
         Box::new(Self::new(voices, node))
     }
-    fn update_notes(&mut self, dropped: Vec<u8>, new: Vec<(u8, f64)>) {
-        for note in dropped {
-            for i in 0..self.last_notes.len() {
-                let last_note = &mut self.last_notes[i];
-                if last_note.0 == note && last_note.2 {
+    /// Sets the pitch bend range in semitones. The default is 2 semitones, as in General MIDI.
+    pub fn with_bend_range(mut self, semitones: f64) -> Self {
+        self.bend_range = semitones;
+        self
+    }
+    fn handle_message(&mut self, msg: MsgType) {
+        match msg {
+            MsgType::NoteOn(pitch, vel) => self.start_note(pitch, vel as f64 / 127.0),
+            MsgType::NoteOff(pitch) => self.stop_note(pitch),
+            MsgType::PitchBend(bend) => self.pitch_bend = bend,
+            MsgType::Control(controller, value) => self.update_control(controller, value),
+            _ => (),
+        }
+    }
+    fn update_control(&mut self, controller: u8, value: u8) {
+        let pressed = value >= 64;
+        match controller {
+            CC_MODULATION => self.modulation = value as f64 / 127.0,
+            CC_SUSTAIN if pressed != self.sustain => {
+                self.sustain = pressed;
+                if !pressed {
+                    self.release_held();
+                }
+            }
+            CC_SOSTENUTO if pressed != self.sostenuto => {
+                self.sostenuto = pressed;
+                for i in 0..self.held.len() {
+                    let (released, _) = self.held[i];
+                    self.held[i].1 = pressed && self.last_notes[i].2 && !released;
+                }
+                if !pressed {
+                    self.release_held();
+                }
+            }
+            _ => (),
+        }
+    }
+    fn release_held(&mut self) {
+        for i in 0..self.held.len() {
+            let (released, caught) = self.held[i];
+            if released && !caught && !self.sustain {
+                self.last_notes[i].2 = false;
+                self.held[i].0 = false;
+            }
+        }
+    }
+    fn stop_note(&mut self, pitch: u8) {
+        for i in 0..self.last_notes.len() {
+            let last_note = &mut self.last_notes[i];
+            if last_note.0 == pitch && last_note.2 && !self.held[i].0 {
+                if self.sustain || self.held[i].1 {
+                    self.held[i].0 = true;
+                } else {
                     last_note.2 = false;
                 }
             }
         }
-        for note in new {
-            self.voices[self.voice_index].reset();
-            self.last_notes[self.voice_index] = (note.0, note.1, true);
-            self.voice_index = (self.voice_index + 1) % self.voices.len();
-            self.detunes[self.voice_index] = self.rng.gen_range(-0.004..0.004);
+    }
+    fn start_note(&mut self, pitch: u8, vel: f64) {
+        self.voices[self.voice_index].reset();
+        self.last_notes[self.voice_index] = (pitch, vel, true);
+        self.held[self.voice_index] = (false, false);
+        self.voice_index = (self.voice_index + 1) % self.voices.len();
+        self.detunes[self.voice_index] = self.rng.gen_range(-0.004..0.004);
+    }
+    fn render(&mut self, output: &mut [Frame<f64, U2>]) {
+        let bend = self.pitch_bend * self.bend_range;
+        for frame in output.iter_mut() {
+            let mut mix: Frame<f64, U2> = [0.0, 0.0].into();
+            for i in 0..self.voices.len() {
+                let voice = &mut self.voices[i];
+                let data = self.last_notes[i];
+                let input = [
+                    midi_hz(data.0 as f64 + bend) * (1.0 + self.detunes[i]),
+                    data.1,
+                    if data.2 { 1.0 } else { -1.0 },
+                    self.modulation,
+                ];
+                let mut output: Frame<f64, U2> = [0.0, 0.0].into();
+                voice.tick(&input, &mut output);
+                mix += output;
+            }
+            *frame = mix;
         }
     }
 }
 
 impl Synthesizer for SimpleSynth {
     fn set_midi(&mut self, midi: Vec<MidiMsg>) {
-        self.midi_wrapper = MidiWrapper::new(midi)
+        self.midi_wrapper = MidiWrapper::new(midi);
+        self.midi_wrapper.set_sample_rate(self.sample_rate);
     }
 
-    fn tick(&mut self, time: f64) -> Frame<f64, U2> {
-        let (dropped, new) = self.midi_wrapper.tick(time);
-
-        self.update_notes(dropped, new);
-
-        let mut mix: Frame<f64, U2> = [0.0, 0.0].into();
-        for i in 0..self.voices.len() {
-            let voice = &mut self.voices[i];
-            let data = self.last_notes[i];
-            let input = [
-                midi_hz(data.0 as f64) * (1.0 + self.detunes[i]),
-                data.1,
-                if data.2 { 1.0 } else { -1.0 },
-            ];
-            let mut output: Frame<f64, U2> = [0.0, 0.0].into();
-            voice.tick(&input, &mut output);
-            mix += output;
+    fn process(&mut self, start: usize, output: &mut [Frame<f64, U2>]) {
+        let mut offset = 0;
+        while offset < output.len() {
+            while let Some(msg) = self.midi_wrapper.pop(start + offset) {
+                self.handle_message(msg);
+            }
+            let end = self
+                .midi_wrapper
+                .next_sample()
+                .map_or(output.len(), |x| min(x - start, output.len()));
+            self.render(&mut output[offset..end]);
+            offset = end;
         }
-        mix
     }
     fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        self.midi_wrapper.set_sample_rate(sample_rate);
         for voice in self.voices.iter_mut() {
             voice.set_sample_rate(sample_rate);
         }
@@ -117,7 +224,12 @@ impl Synthesizer for SimpleSynth {
             voice.reset();
         }
         self.last_notes = vec![(0, 0.0, false); self.voices.len()];
+        self.held = vec![(false, false); self.voices.len()];
+        self.sustain = false;
+        self.sostenuto = false;
         self.voice_index = 0;
+        self.pitch_bend = 0.0;
+        self.modulation = 0.0;
         self.midi_wrapper.reset();
     }
 }
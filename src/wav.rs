@@ -0,0 +1,389 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use fundsp::wave::Wave64;
+use rand::prelude::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WavFormat {
+    Int16,
+    Int24,
+    Float32,
+}
+
+impl WavFormat {
+    fn bits(&self) -> u16 {
+        match self {
+            Self::Int16 => 16,
+            Self::Int24 => 24,
+            Self::Float32 => 32,
+        }
+    }
+    /// Returns the size of the data chunk in bytes, or `None` if the RIFF size of a file with
+    /// this data doesn't fit into the 32 bits of the header.
+    fn data_size(&self, channels: usize, length: usize) -> Option<u32> {
+        let block_align = channels as u64 * (self.bits() as u64 / 8);
+        let data_size = (length as u64).checked_mul(block_align)?;
+        u32::try_from(data_size.checked_add(36)?).ok()?;
+        u32::try_from(data_size).ok()
+    }
+}
+
+/// Dithering applied when converting to an integer format. Ignored for float output.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dither {
+    None,
+    /// Triangular probability density dither with a peak amplitude of one LSB.
+    Tpdf,
+    /// TPDF dither with first-order noise shaping, which moves the quantization noise to higher frequencies.
+    TpdfShaped,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct WavOptions {
+    pub format: WavFormat,
+    pub dither: Dither,
+}
+
+impl WavOptions {
+    pub fn new(format: WavFormat, dither: Dither) -> Self {
+        Self { format, dither }
+    }
+}
+
+impl Default for WavOptions {
+    fn default() -> Self {
+        Self::new(WavFormat::Float32, Dither::None)
+    }
+}
+
+/// Saves a wave as a WAV file and returns the number of samples that were clipped.
+/// Float output isn't clipped, so for it the samples that exceeded full scale are counted.
+pub fn save_wav<P>(wave: &Wave64, path: P, options: &WavOptions) -> Result<usize, anyhow::Error>
+where
+    P: AsRef<Path>,
+{
+    let mut writer = BufWriter::new(File::create(path)?);
+    let clipped = write_wav(wave, &mut writer, options)?;
+    writer.flush()?;
+    Ok(clipped)
+}
+
+/// Writes a wave in WAV format and returns the number of samples that were clipped, see `save_wav`.
+pub fn write_wav<W>(
+    wave: &Wave64,
+    writer: &mut W,
+    options: &WavOptions,
+) -> Result<usize, anyhow::Error>
+where
+    W: Write,
+{
+    let channels = wave.channels();
+    write_header(
+        writer,
+        options.format,
+        channels,
+        wave.sample_rate(),
+        wave.len(),
+    )?;
+    let mut quantizer = Quantizer::new(options, channels);
+    for i in 0..wave.len() {
+        for channel in 0..channels {
+            quantizer.write(writer, channel, wave.at(channel, i))?;
+        }
+    }
+    Ok(quantizer.clipped)
+}
+
+/// Writes a WAV file incrementally, frame by frame.
+/// The header is written with a length of zero and patched in `finish`, so the writer must be seekable.
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    quantizer: Quantizer,
+    format: WavFormat,
+    channels: usize,
+    sample_rate: f64,
+    length: usize,
+}
+
+impl WavWriter<BufWriter<File>> {
+    pub fn create<P>(
+        path: P,
+        options: &WavOptions,
+        channels: usize,
+        sample_rate: f64,
+    ) -> Result<Self, anyhow::Error>
+    where
+        P: AsRef<Path>,
+    {
+        Self::new(
+            BufWriter::new(File::create(path)?),
+            options,
+            channels,
+            sample_rate,
+        )
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(
+        mut writer: W,
+        options: &WavOptions,
+        channels: usize,
+        sample_rate: f64,
+    ) -> Result<Self, anyhow::Error> {
+        write_header(&mut writer, options.format, channels, sample_rate, 0)?;
+        Ok(Self {
+            writer,
+            quantizer: Quantizer::new(options, channels),
+            format: options.format,
+            channels,
+            sample_rate,
+            length: 0,
+        })
+    }
+    /// Writes one frame, which must contain a sample for every channel.
+    /// Fails if the file would exceed the 4 GiB that the WAV header can describe.
+    pub fn write_frame(&mut self, frame: &[f64]) -> Result<(), anyhow::Error> {
+        anyhow::ensure!(
+            frame.len() == self.channels,
+            "Expected {} channels, got {}.",
+            self.channels,
+            frame.len()
+        );
+        anyhow::ensure!(
+            self.format
+                .data_size(self.channels, self.length + 1)
+                .is_some(),
+            "The WAV file can't hold more than {} frames.",
+            self.length
+        );
+        for (channel, sample) in frame.iter().enumerate() {
+            self.quantizer.write(&mut self.writer, channel, *sample)?;
+        }
+        self.length += 1;
+        Ok(())
+    }
+    /// Returns the number of frames written so far.
+    pub fn len(&self) -> usize {
+        self.length
+    }
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+    /// Returns the number of samples that were clipped so far.
+    pub fn clipped(&self) -> usize {
+        self.quantizer.clipped
+    }
+    /// Patches the header with the final length and flushes the writer.
+    /// Returns the writer and the number of samples that were clipped.
+    pub fn finish(mut self) -> Result<(W, usize), anyhow::Error> {
+        self.writer.seek(SeekFrom::Start(0))?;
+        write_header(
+            &mut self.writer,
+            self.format,
+            self.channels,
+            self.sample_rate,
+            self.length,
+        )?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok((self.writer, self.quantizer.clipped))
+    }
+}
+
+pub(crate) fn write_header<W>(
+    writer: &mut W,
+    format: WavFormat,
+    channels: usize,
+    sample_rate: f64,
+    length: usize,
+) -> Result<(), anyhow::Error>
+where
+    W: Write,
+{
+    let bytes_per_sample = format.bits() as u32 / 8;
+    let block_align = channels as u32 * bytes_per_sample;
+    let Some(data_size) = format.data_size(channels, length) else {
+        anyhow::bail!(
+            "{} frames don't fit into a WAV file, which is limited to 4 GiB.",
+            length
+        );
+    };
+    let sample_rate = sample_rate.round() as u32;
+    let tag: u16 = if format == WavFormat::Float32 { 3 } else { 1 };
+
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(36 + data_size).to_le_bytes())?;
+    writer.write_all(b"WAVEfmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?;
+    writer.write_all(&tag.to_le_bytes())?;
+    writer.write_all(&(channels as u16).to_le_bytes())?;
+    writer.write_all(&sample_rate.to_le_bytes())?;
+    writer.write_all(&(sample_rate * block_align).to_le_bytes())?;
+    writer.write_all(&(block_align as u16).to_le_bytes())?;
+    writer.write_all(&format.bits().to_le_bytes())?;
+    writer.write_all(b"data")?;
+    writer.write_all(&data_size.to_le_bytes())?;
+    Ok(())
+}
+
+/// Converts samples to the output format, applying dither and noise shaping and counting clipped samples.
+/// Float samples are written as they are and counted if they exceed full scale.
+pub(crate) struct Quantizer {
+    format: WavFormat,
+    dither: Dither,
+    rng: StdRng,
+    errors: Vec<f64>,
+    pub clipped: usize,
+}
+
+impl Quantizer {
+    pub fn new(options: &WavOptions, channels: usize) -> Self {
+        Self {
+            format: options.format,
+            dither: options.dither,
+            rng: StdRng::seed_from_u64(0),
+            errors: vec![0.0; channels],
+            clipped: 0,
+        }
+    }
+    pub fn write<W>(
+        &mut self,
+        writer: &mut W,
+        channel: usize,
+        sample: f64,
+    ) -> Result<(), std::io::Error>
+    where
+        W: Write,
+    {
+        let scale = match self.format {
+            WavFormat::Int16 => 32767.0,
+            WavFormat::Int24 => 8388607.0,
+            WavFormat::Float32 => {
+                if sample.abs() > 1.0 {
+                    self.clipped += 1;
+                }
+                return writer.write_all(&(sample as f32).to_le_bytes());
+            }
+        };
+
+        let shaped = match self.dither {
+            Dither::TpdfShaped => sample * scale - self.errors[channel],
+            _ => sample * scale,
+        };
+        let noise = match self.dither {
+            Dither::None => 0.0,
+            _ => self.rng.gen::<f64>() - self.rng.gen::<f64>(),
+        };
+        let quantized = (shaped + noise).round();
+
+        let value = if quantized < -scale - 1.0 || quantized > scale {
+            // Feeding back the clipping error would make the noise shaping unstable.
+            self.clipped += 1;
+            self.errors[channel] = 0.0;
+            quantized.clamp(-scale - 1.0, scale)
+        } else {
+            self.errors[channel] = quantized - shaped;
+            quantized
+        } as i32;
+
+        match self.format {
+            WavFormat::Int16 => writer.write_all(&(value as i16).to_le_bytes()),
+            _ => writer.write_all(&value.to_le_bytes()[..3]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn stereo_wave(length: usize) -> Wave64 {
+        let samples: Vec<f64> = (0..length).map(|i| i as f64 / length as f64).collect();
+        let mut wave = Wave64::new(0, 48000.0);
+        wave.push_channel(&samples);
+        wave.push_channel(&samples);
+        wave
+    }
+
+    #[test]
+    fn header_and_data_size() {
+        for (format, bits, tag) in [
+            (WavFormat::Int16, 16, 1),
+            (WavFormat::Int24, 24, 1),
+            (WavFormat::Float32, 32, 3),
+        ] {
+            let options = WavOptions::new(format, Dither::Tpdf);
+            let mut bytes = Vec::new();
+            write_wav(&stereo_wave(100), &mut bytes, &options).unwrap();
+
+            let data_size = 100 * 2 * bits as u32 / 8;
+            assert_eq!(bytes.len(), 44 + data_size as usize);
+            assert_eq!(&bytes[0..4], b"RIFF");
+            assert_eq!(u32_at(&bytes, 4), 36 + data_size);
+            assert_eq!(&bytes[8..16], b"WAVEfmt ");
+            assert_eq!(u16_at(&bytes, 20), tag);
+            assert_eq!(u16_at(&bytes, 22), 2);
+            assert_eq!(u32_at(&bytes, 24), 48000);
+            assert_eq!(u32_at(&bytes, 28), 48000 * 2 * bits as u32 / 8);
+            assert_eq!(u16_at(&bytes, 32), 2 * bits / 8);
+            assert_eq!(u16_at(&bytes, 34), bits);
+            assert_eq!(&bytes[36..40], b"data");
+            assert_eq!(u32_at(&bytes, 40), data_size);
+
+            // Streaming patches the header to the same file.
+            let mut writer = WavWriter::new(Cursor::new(Vec::new()), &options, 2, 48000.0).unwrap();
+            let wave = stereo_wave(100);
+            for i in 0..wave.len() {
+                writer.write_frame(&[wave.at(0, i), wave.at(1, i)]).unwrap();
+            }
+            let (cursor, _) = writer.finish().unwrap();
+            assert_eq!(cursor.into_inner(), bytes);
+        }
+    }
+
+    #[test]
+    fn data_size_is_limited_to_4_gib() {
+        let max_frames = (u32::MAX as usize - 36) / 4;
+        assert!(WavFormat::Int16.data_size(2, max_frames).is_some());
+        assert!(WavFormat::Int16.data_size(2, max_frames + 1).is_none());
+        assert!(WavFormat::Float32.data_size(2, usize::MAX).is_none());
+        assert!(write_header(&mut Vec::new(), WavFormat::Int24, 2, 48000.0, usize::MAX).is_err());
+    }
+
+    #[test]
+    fn quantizer_counts_clipped_samples() {
+        for format in [WavFormat::Int16, WavFormat::Int24] {
+            let mut quantizer = Quantizer::new(&WavOptions::new(format, Dither::None), 1);
+            let mut bytes = Vec::new();
+            for sample in [0.5, 1.0, -1.0, 1.5, -2.0] {
+                quantizer.write(&mut bytes, 0, sample).unwrap();
+            }
+            assert_eq!(quantizer.clipped, 2);
+        }
+
+        let mut quantizer = Quantizer::new(&WavOptions::new(WavFormat::Int16, Dither::None), 1);
+        let mut bytes = Vec::new();
+        quantizer.write(&mut bytes, 0, 1.5).unwrap();
+        assert_eq!(bytes, 32767i16.to_le_bytes());
+
+        // Float samples beyond full scale are counted, but written as they are.
+        let mut quantizer = Quantizer::new(&WavOptions::default(), 1);
+        let mut bytes = Vec::new();
+        quantizer.write(&mut bytes, 0, 1.5).unwrap();
+        quantizer.write(&mut bytes, 0, -0.5).unwrap();
+        assert_eq!(quantizer.clipped, 1);
+        assert_eq!(&bytes[..4], 1.5f32.to_le_bytes());
+    }
+}